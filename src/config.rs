@@ -0,0 +1,528 @@
+//! Configuration file with default options and named profiles.
+//!
+//! The file is a small subset of TOML: `key = value` lines with strings, integers, floats, booleans
+//! and arrays of those (which may span several lines), plus `[profile.<name>]` tables. Inline
+//! tables, dotted keys and multi-line strings are not supported. Top-level keys apply to every run, and keys under
+//! `[profile.<name>]` override them when `--profile <name>` is given, so `force = false` in a
+//! profile turns off a `force = true` at the top. Keys are the long option names (`target-size`,
+//! `force`, ...). Their values are only used for options that are neither given on the command
+//! line nor in conflict with one that is. A `preset` given on the command line, or in a profile,
+//! also drops the sizes and strategies set by earlier layers, so that the preset's own apply.
+//! Keys that require another option (`dither` needs `palette`) are dropped when it is not given;
+//! see `missing_args` in `main.rs`.
+//!
+//! ```toml
+//! target-size = "15MiB"
+//! wait = true
+//!
+//! [profile.discord]
+//! preset = "discord"
+//! margin = "2%"
+//! ```
+
+use crate::preset;
+use clap::{ArgAction, Command};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// Options that only make sense on the command line.
+pub const CLI_ONLY: &[&str] = &["config", "profile", "print-config", "help"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{}:{line}: {message}", path.display())]
+    Syntax {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+
+    #[error("{}: unknown key \"{key}\"", path.display())]
+    UnknownKey { path: PathBuf, key: String },
+
+    #[error("{}: invalid value for \"{key}\": {message}", path.display())]
+    InvalidValue {
+        path: PathBuf,
+        key: String,
+        message: &'static str,
+    },
+
+    #[error("profile \"{0}\" is not defined in the config file")]
+    UnknownProfile(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    /// Formats the value as a command line argument; arrays become comma-separated lists.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => f.write_str(s),
+            Value::Integer(n) => write!(f, "{n}"),
+            Value::Float(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Array(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parsed contents of a config file.
+#[derive(Debug, Default)]
+pub struct Config {
+    path: PathBuf,
+    defaults: Vec<(String, Value)>,
+    profiles: BTreeMap<String, Vec<(String, Value)>>,
+}
+
+/// The default config file location: `$XDG_CONFIG_HOME/reenc-image/config.toml`, falling back to
+/// `~/.config` (or `%APPDATA%` on Windows).
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("APPDATA").map(PathBuf::from)
+            } else {
+                std::env::var_os("HOME").map(|home| Path::new(&home).join(".config"))
+            }
+        })?;
+    Some(base.join("reenc-image").join("config.toml"))
+}
+
+impl Config {
+    /// Loads a config file; a missing file is only an error if `required` is set.
+    pub fn load(path: &Path, required: bool) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(path, &text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Self {
+                path: path.to_owned(),
+                ..Self::default()
+            }),
+            Err(source) => Err(ConfigError::Io {
+                path: path.to_owned(),
+                source,
+            }),
+        }
+    }
+
+    pub fn parse(path: &Path, text: &str) -> Result<Self, ConfigError> {
+        let mut config = Self {
+            path: path.to_owned(),
+            ..Self::default()
+        };
+        let syntax = |line: usize, message: &str| ConfigError::Syntax {
+            path: path.to_owned(),
+            line,
+            message: message.to_owned(),
+        };
+
+        let mut section = &mut config.defaults;
+        let mut lines = text.lines().enumerate();
+        while let Some((i, line)) = lines.next() {
+            let line_no = i + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| syntax(line_no, "unterminated table header"))?
+                    .trim();
+                let name = header
+                    .strip_prefix("profile.")
+                    .map(|name| unquote(name.trim()))
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| syntax(line_no, "expected [profile.<name>]"))?;
+                section = config.profiles.entry(name.to_owned()).or_default();
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| syntax(line_no, "expected key = value"))?;
+            let key = unquote(key.trim()).replace('_', "-");
+            let mut value = value.trim().to_owned();
+            // An array continues until a line ends with its closing bracket
+            while value.starts_with('[') && !value.ends_with(']') {
+                let (_, next) = lines
+                    .next()
+                    .ok_or_else(|| syntax(line_no, "unterminated array"))?;
+                value.push(' ');
+                value.push_str(strip_comment(next).trim());
+            }
+            let value = parse_value(&value).map_err(|message| syntax(line_no, message))?;
+            section.push((key, value));
+        }
+        Ok(config)
+    }
+
+    /// Turns the defaults and the given profile into command line arguments for `command`,
+    /// leaving out the options in `given` (the ids of those on the command line) and the ones
+    /// that conflict with them. A preset in a later layer replaces the options it sets.
+    pub fn to_args(
+        &self,
+        command: &Command,
+        profile: Option<&str>,
+        given: &[&str],
+    ) -> Result<Vec<OsString>, ConfigError> {
+        let mut entries: Vec<&(String, Value)> = Vec::new();
+        let profile = match profile {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .ok_or_else(|| ConfigError::UnknownProfile(name.to_owned()))?,
+            None => &Vec::new(),
+        };
+        let given_args: Vec<_> = command
+            .get_arguments()
+            .filter(|arg| given.contains(&arg.get_id().as_str()))
+            .collect();
+        let given_preset = given_args
+            .iter()
+            .any(|arg| arg.get_long() == Some("preset"));
+
+        for layer in [&self.defaults, profile] {
+            if layer.iter().any(|(key, _)| key == "preset") {
+                entries.retain(|(key, _)| !preset::OPTIONS.contains(&key.as_str()));
+            }
+            // A later value for the same key replaces the earlier one
+            for entry in layer {
+                entries.retain(|(key, _)| *key != entry.0);
+                entries.push(entry);
+            }
+        }
+        if given_preset {
+            entries.retain(|(key, _)| !preset::OPTIONS.contains(&key.as_str()));
+        }
+        let mut args = Vec::new();
+        for (key, value) in entries {
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(key.as_str()))
+                .filter(|_| !CLI_ONLY.contains(&key.as_str()))
+                .ok_or_else(|| ConfigError::UnknownKey {
+                    path: self.path.clone(),
+                    key: key.clone(),
+                })?;
+            let overridden = given_args.iter().any(|given| {
+                given.get_id() == arg.get_id()
+                    || command.get_arg_conflicts_with(arg).contains(given)
+                    || command.get_arg_conflicts_with(given).contains(&arg)
+            });
+            if matches!(arg.get_action(), ArgAction::SetTrue) {
+                match value {
+                    Value::Bool(true) if !overridden => args.push(format!("--{key}").into()),
+                    Value::Bool(_) => {}
+                    _ => {
+                        return Err(ConfigError::InvalidValue {
+                            path: self.path.clone(),
+                            key: key.clone(),
+                            message: "expected true or false",
+                        });
+                    }
+                }
+            } else if !overridden {
+                // `--key=value`, so that values starting with `-` are not taken as options
+                args.push(format!("--{key}={value}").into());
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Formats a command line value for the config file: numbers and booleans as they are, anything
+/// else as a string.
+pub fn format_value(value: &str) -> String {
+    if matches!(value, "true" | "false") || value.parse::<i64>().is_ok() {
+        return value.to_owned();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Removes a `#` comment, unless the `#` is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), _) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .or_else(|| s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')))
+        .unwrap_or(s)
+}
+
+fn parse_value(s: &str) -> Result<Value, &'static str> {
+    if let Some(inner) = s.strip_prefix('[') {
+        let inner = inner.strip_suffix(']').ok_or("unterminated array")?;
+        return split_array(inner)
+            .into_iter()
+            .map(|item| parse_value(item.trim()))
+            .collect::<Result<_, _>>()
+            .map(Value::Array);
+    }
+    if let Some(rest) = s.strip_prefix('"') {
+        let body = rest.strip_suffix('"').ok_or("unterminated string")?;
+        return unescape(body).map(Value::String);
+    }
+    if let Some(rest) = s.strip_prefix('\'') {
+        let body = rest.strip_suffix('\'').ok_or("unterminated string")?;
+        return Ok(Value::String(body.to_owned()));
+    }
+    match s {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    let number = s.replace('_', "");
+    if let Ok(n) = number.parse() {
+        return Ok(Value::Integer(n));
+    }
+    if let Ok(n) = number.parse() {
+        return Ok(Value::Float(n));
+    }
+    Err("expected a string, number, boolean or array")
+}
+
+/// Splits the inside of an array at the commas that are not inside strings.
+fn split_array(inner: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ',') => {
+                items.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&inner[start..]);
+    // Allow a trailing comma
+    if items.last().is_some_and(|item| item.trim().is_empty()) {
+        items.pop();
+    }
+    items
+}
+
+fn unescape(s: &str) -> Result<String, &'static str> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => out.push('"'),
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            _ => return Err("unsupported escape sequence"),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(clap::Arg::new("target-size").long("target-size"))
+            .arg(clap::Arg::new("strategy").long("strategy"))
+            .arg(
+                clap::Arg::new("force")
+                    .long("force")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(clap::Arg::new("profile").long("profile"))
+            .arg(clap::Arg::new("preset").long("preset"))
+            .arg(clap::Arg::new("name-template").long("name-template"))
+            .arg(
+                clap::Arg::new("in-place")
+                    .long("in-place")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("name-template"),
+            )
+    }
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(Path::new("config.toml"), text)
+    }
+
+    // The profile's keys replace the top-level ones, including `false` for a flag.
+    #[test]
+    fn test_defaults_then_profile() {
+        let config = parse(
+            r#"
+            # defaults
+            target_size = "15MiB"
+            force = true
+
+            [profile.bluesky]
+            target-size = "1MB" # inline comment
+            strategy = ["png", "jpeg:95"]
+            force = false
+            "#,
+        )
+        .unwrap();
+
+        let args = config.to_args(&command(), Some("bluesky"), &[]).unwrap();
+
+        assert_eq!(args, ["--target-size=1MB", "--strategy=png,jpeg:95"]);
+        assert_eq!(
+            config.to_args(&command(), None, &[]).unwrap(),
+            ["--target-size=15MiB", "--force"]
+        );
+    }
+
+    // Options on the command line, and the ones conflicting with them, are not taken from the
+    // file.
+    #[test]
+    fn test_command_line_takes_precedence() {
+        let config = parse(
+            r#"
+            target-size = "15MiB"
+            name-template = "{stem}.{ext}"
+            force = true
+            "#,
+        )
+        .unwrap();
+
+        let args = config
+            .to_args(&command(), None, &["target-size", "in-place"])
+            .unwrap();
+
+        assert_eq!(args, ["--force"]);
+    }
+
+    // A preset on the command line or in the profile replaces the target size and strategies
+    // from the top level, but not the ones next to it.
+    #[test]
+    fn test_preset_replaces_earlier_layers() {
+        let config = parse(
+            r#"
+            target-size = "15MiB"
+            strategy = ["png"]
+            force = true
+
+            [profile.bsky]
+            preset = "bluesky"
+
+            [profile.small]
+            preset = "bluesky"
+            target-size = "500k"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.to_args(&command(), Some("bsky"), &[]).unwrap(),
+            ["--force", "--preset=bluesky"]
+        );
+        assert_eq!(
+            config.to_args(&command(), None, &["preset"]).unwrap(),
+            ["--force"]
+        );
+        assert_eq!(
+            config.to_args(&command(), Some("small"), &[]).unwrap(),
+            ["--force", "--preset=bluesky", "--target-size=500k"]
+        );
+    }
+
+    // Keys that are not options, or only make sense on the command line, are rejected.
+    #[test]
+    fn test_unknown_keys_and_profiles() {
+        let config = parse("colour = \"red\"\n").unwrap();
+        assert!(matches!(
+            config.to_args(&command(), None, &[]),
+            Err(ConfigError::UnknownKey { key, .. }) if key == "colour"
+        ));
+
+        let config = parse("profile = \"x\"\n").unwrap();
+        assert!(config.to_args(&command(), None, &[]).is_err());
+
+        let config = parse("").unwrap();
+        assert!(matches!(
+            config.to_args(&command(), Some("nope"), &[]),
+            Err(ConfigError::UnknownProfile(_))
+        ));
+    }
+
+    // Arrays may be split over several lines, with comments and a trailing comma.
+    #[test]
+    fn test_multi_line_array() {
+        let config = parse(
+            r#"
+            strategy = [
+                "png", # lossless first
+                "jpeg:95",
+            ]
+            force = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.to_args(&command(), None, &[]).unwrap(),
+            ["--strategy=png,jpeg:95", "--force"]
+        );
+        assert!(matches!(
+            parse("strategy = [\n\"png\",\n"),
+            Err(ConfigError::Syntax { line: 1, .. })
+        ));
+    }
+
+    // Syntax errors point at the offending line.
+    #[test]
+    fn test_syntax_error_line() {
+        let error = parse("force = true\n[profile.x\n").unwrap_err();
+
+        assert!(matches!(error, ConfigError::Syntax { line: 2, .. }));
+        assert!(parse("target-size = \"15MiB\n").is_err());
+    }
+}
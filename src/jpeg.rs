@@ -0,0 +1,862 @@
+//! A small JPEG encoder with selectable chroma subsampling and progressive mode.
+//!
+//! The `image` crate's `JpegEncoder` always writes baseline 4:4:4, so this one exists to make
+//! those configurable. Huffman tables are optimized per image, which also makes the output
+//! a bit smaller than with the standard tables.
+//!
+//! The `jpeg-encoder` crate has both options as well, but it only takes pixels: every probe of the
+//! quality search would redo the color conversion and the DCT, which take most of the time. Here
+//! they are done once per image ([`Transformed`]) and only the quantization and entropy coding
+//! run per quality. Doing it ourselves also keeps the dependencies to the ones `image` brings.
+
+use crate::metadata::Metadata;
+use image::{
+    DynamicImage,
+    error::{ImageError, LimitError, LimitErrorKind},
+};
+use std::fmt;
+
+/// Chroma subsampling mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Subsampling {
+    /// No subsampling; keeps colored text and thin lines sharp
+    #[clap(name = "444")]
+    S444,
+    /// Half horizontal chroma resolution
+    #[clap(name = "422")]
+    S422,
+    /// Half horizontal and vertical chroma resolution; the smallest output
+    #[clap(name = "420")]
+    S420,
+}
+
+impl fmt::Display for Subsampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use clap::ValueEnum;
+        let value = self.to_possible_value().expect("no variant is skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+impl Subsampling {
+    /// Sampling factors (horizontal, vertical) of the luma component.
+    fn luma_factors(self) -> (usize, usize) {
+        match self {
+            Subsampling::S444 => (1, 1),
+            Subsampling::S422 => (2, 1),
+            Subsampling::S420 => (2, 2),
+        }
+    }
+}
+
+/// Zigzag order: `ZIGZAG[i]` is the natural (row-major) index of the i-th coefficient.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Base quantization tables from the JPEG spec (Annex K), in natural order.
+const LUMA_QUANT: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMA_QUANT: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// One scan: a set of components and the band of zigzag coefficients `start..=end` it codes.
+struct Scan {
+    components: Vec<usize>,
+    start: u8,
+    end: u8,
+}
+
+impl Scan {
+    fn new(components: impl Into<Vec<usize>>, start: u8, end: u8) -> Self {
+        Self {
+            components: components.into(),
+            start,
+            end,
+        }
+    }
+}
+
+/// An image converted to YCbCr, subsampled and transformed with the DCT.
+///
+/// Only the quantization and the entropy coding depend on the quality, so a quality search
+/// transforms the image once and encodes it from here at each quality it tries.
+pub struct Transformed {
+    frame: Frame<f32>,
+    width: usize,
+    height: usize,
+}
+
+impl Transformed {
+    /// Transforms `image`. Grayscale images get a single component, in which case `subsampling`
+    /// has no effect.
+    pub fn new(image: &DynamicImage, subsampling: Subsampling) -> Result<Self, ImageError> {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        if width == 0 || height == 0 || width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(ImageError::Limits(LimitError::from_kind(
+                LimitErrorKind::DimensionError,
+            )));
+        }
+        let grayscale = !image.color().has_color();
+        Ok(Self {
+            frame: Frame::new(image, grayscale, subsampling),
+            width,
+            height,
+        })
+    }
+
+    /// Encodes the image as a JPEG.
+    ///
+    /// `quality` follows the libjpeg scale (1-100). Progressive output uses spectral selection
+    /// only: the DC coefficients first, then the low and high AC bands. The EXIF block of
+    /// `metadata` goes into an APP1 segment if it fits in one, the ICC profile into APP2
+    /// segments.
+    pub fn encode(&self, quality: u8, progressive: bool, metadata: &Metadata) -> Vec<u8> {
+        let tables = [
+            scale_quant(&LUMA_QUANT, quality),
+            scale_quant(&CHROMA_QUANT, quality),
+        ];
+        let frame = self.frame.quantize(&tables);
+        write_jpeg(
+            &frame,
+            &tables[..frame.components.len().min(2)],
+            (self.width, self.height),
+            progressive,
+            metadata,
+        )
+    }
+}
+
+/// Writes the whole file for a quantized frame.
+fn write_jpeg(
+    frame: &Frame<i16>,
+    tables: &[[u16; 64]],
+    (width, height): (usize, usize),
+    progressive: bool,
+    metadata: &Metadata,
+) -> Vec<u8> {
+    let grayscale = frame.components.len() == 1;
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    write_jfif(&mut out);
+    if let Some(exif) = &metadata.exif {
+        write_exif(&mut out, exif);
+    }
+    if let Some(icc_profile) = &metadata.icc_profile {
+        write_icc_profile(&mut out, icc_profile);
+    }
+    write_quant_tables(&mut out, tables);
+    write_frame_header(&mut out, frame, width, height, progressive);
+    let all: Vec<_> = (0..frame.components.len()).collect();
+    let scans = if !progressive {
+        vec![Scan::new(all, 0, 63)]
+    } else if grayscale {
+        vec![
+            Scan::new(all, 0, 0),
+            Scan::new([0], 1, 5),
+            Scan::new([0], 6, 63),
+        ]
+    } else {
+        vec![
+            Scan::new(all, 0, 0),
+            Scan::new([0], 1, 5),
+            Scan::new([1], 1, 63),
+            Scan::new([2], 1, 63),
+            Scan::new([0], 6, 63),
+        ]
+    };
+    for scan in &scans {
+        frame.write_scan(&mut out, scan);
+    }
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    out
+}
+
+/// Scales a base quantization table with the libjpeg quality formula.
+fn scale_quant(base: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+    base.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// One color component, with its blocks of DCT coefficients in zigzag order: `f32` as
+/// transformed, `i16` once quantized.
+struct Component<T> {
+    id: u8,
+    h: usize,
+    v: usize,
+    table: usize,
+    /// Number of blocks per row in the MCU-padded plane
+    stride: usize,
+    /// Number of blocks that actually cover the image, used by non-interleaved scans
+    blocks_x: usize,
+    blocks_y: usize,
+    blocks: Vec<[T; 64]>,
+}
+
+struct Frame<T> {
+    components: Vec<Component<T>>,
+    mcus_x: usize,
+    mcus_y: usize,
+}
+
+impl Frame<f32> {
+    fn new(image: &DynamicImage, grayscale: bool, subsampling: Subsampling) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let (h_max, v_max) = if grayscale {
+            (1, 1)
+        } else {
+            subsampling.luma_factors()
+        };
+        let mcus_x = width.div_ceil(8 * h_max);
+        let mcus_y = height.div_ceil(8 * v_max);
+        let (padded_w, padded_h) = (mcus_x * 8 * h_max, mcus_y * 8 * v_max);
+
+        let planes = if grayscale {
+            let luma = image.to_luma8();
+            vec![pad_plane(
+                luma.as_raw(),
+                width,
+                height,
+                padded_w,
+                padded_h,
+                |p| p[0] as f32,
+                1,
+            )]
+        } else {
+            let rgb = image.to_rgb8();
+            let channel = |f: fn(f32, f32, f32) -> f32| {
+                pad_plane(
+                    rgb.as_raw(),
+                    width,
+                    height,
+                    padded_w,
+                    padded_h,
+                    move |p| f(p[0] as f32, p[1] as f32, p[2] as f32),
+                    3,
+                )
+            };
+            vec![
+                channel(|r, g, b| 0.299 * r + 0.587 * g + 0.114 * b),
+                channel(|r, g, b| -0.168_736 * r - 0.331_264 * g + 0.5 * b + 128.0),
+                channel(|r, g, b| 0.5 * r - 0.418_688 * g - 0.081_312 * b + 128.0),
+            ]
+        };
+
+        let components = planes
+            .into_iter()
+            .enumerate()
+            .map(|(i, plane)| {
+                let (h, v) = if i == 0 { (h_max, v_max) } else { (1, 1) };
+                let plane = downsample(&plane, padded_w, h_max / h, v_max / v);
+                let (plane_w, plane_h) = (padded_w * h / h_max, padded_h * v / v_max);
+                let table = i.min(1);
+                Component {
+                    id: i as u8 + 1,
+                    h,
+                    v,
+                    table,
+                    stride: plane_w / 8,
+                    blocks_x: (width * h).div_ceil(h_max).div_ceil(8),
+                    blocks_y: (height * v).div_ceil(v_max).div_ceil(8),
+                    blocks: transform_plane(&plane, plane_w, plane_h),
+                }
+            })
+            .collect();
+
+        Self {
+            components,
+            mcus_x,
+            mcus_y,
+        }
+    }
+
+    /// Divides the coefficients by the quantization table of each component.
+    fn quantize(&self, tables: &[[u16; 64]; 2]) -> Frame<i16> {
+        let components = self
+            .components
+            .iter()
+            .map(|c| {
+                let table = &tables[c.table];
+                let blocks = c
+                    .blocks
+                    .iter()
+                    .map(|block| {
+                        std::array::from_fn(|i| {
+                            // 8-bit baseline coefficients must fit in 10 bits (11 for DC
+                            // differences)
+                            (block[i] / table[ZIGZAG[i]] as f32)
+                                .round()
+                                .clamp(-1023.0, 1023.0) as i16
+                        })
+                    })
+                    .collect();
+                Component {
+                    id: c.id,
+                    h: c.h,
+                    v: c.v,
+                    table: c.table,
+                    stride: c.stride,
+                    blocks_x: c.blocks_x,
+                    blocks_y: c.blocks_y,
+                    blocks,
+                }
+            })
+            .collect();
+        Frame {
+            components,
+            mcus_x: self.mcus_x,
+            mcus_y: self.mcus_y,
+        }
+    }
+}
+
+impl Frame<i16> {
+    /// Writes the Huffman tables and one scan.
+    fn write_scan(&self, out: &mut Vec<u8>, scan: &Scan) {
+        let mut stats = [[[0u32; 257]; 2]; 2];
+        self.walk_scan(scan, |class, table, symbol, _, _| {
+            stats[class][table][symbol as usize] += 1;
+        });
+
+        let mut codes = [[[(0u16, 0u8); 256]; 2]; 2];
+        let mut dht = Vec::new();
+        for class in 0..2 {
+            for table in 0..2 {
+                if stats[class][table].iter().any(|&n| n > 0) {
+                    let (bits, values) = optimal_table(&stats[class][table]);
+                    codes[class][table] = code_table(&bits, &values);
+                    dht.push((class << 4 | table) as u8);
+                    dht.extend_from_slice(&bits);
+                    dht.extend_from_slice(&values);
+                }
+            }
+        }
+        write_segment(out, 0xC4, &dht);
+
+        let mut sos = vec![scan.components.len() as u8];
+        for &c in &scan.components {
+            let table = self.components[c].table as u8;
+            let dc = if scan.start == 0 { table } else { 0 };
+            let ac = if scan.end > 0 { table } else { 0 };
+            sos.extend_from_slice(&[self.components[c].id, dc << 4 | ac]);
+        }
+        sos.extend_from_slice(&[scan.start, scan.end, 0]);
+        write_segment(out, 0xDA, &sos);
+
+        let mut writer = BitWriter::new(out);
+        self.walk_scan(scan, |class, table, symbol, extra, extra_len| {
+            let (code, len) = codes[class][table][symbol as usize];
+            writer.write(code as u32, len);
+            writer.write(extra, extra_len);
+        });
+        writer.flush();
+    }
+
+    /// Visits every Huffman symbol of a scan, in bitstream order.
+    ///
+    /// `emit` receives the table class (0 = DC, 1 = AC), the table index, the symbol and the
+    /// extra bits following it.
+    fn walk_scan(&self, scan: &Scan, mut emit: impl FnMut(usize, usize, u8, u32, u8)) {
+        let mut predictions = vec![0i16; self.components.len()];
+        // Number of pending all-zero blocks in a progressive AC scan, which has a single component
+        let mut eob_run = 0u16;
+        let eob_table = self.components[scan.components[0]].table;
+        let flush_eob_run = |eob_run: &mut u16, emit: &mut dyn FnMut(usize, usize, u8, u32, u8)| {
+            if *eob_run > 0 {
+                let size = (15 - eob_run.leading_zeros()) as u8;
+                emit(1, eob_table, size << 4, *eob_run as u32, size);
+                *eob_run = 0;
+            }
+        };
+
+        for (c, block) in self.scan_blocks(&scan.components) {
+            let table = self.components[c].table;
+            if scan.start == 0 {
+                let diff = block[0] - predictions[c];
+                predictions[c] = block[0];
+                let (size, bits) = magnitude(diff);
+                emit(0, table, size, bits, size);
+            }
+            if scan.end == 0 {
+                continue;
+            }
+
+            let band = &block[scan.start.max(1) as usize..=scan.end as usize];
+            if scan.start > 0 {
+                if band.iter().all(|&coef| coef == 0) {
+                    eob_run += 1;
+                    if eob_run == 0x7FFF {
+                        flush_eob_run(&mut eob_run, &mut emit);
+                    }
+                    continue;
+                }
+                flush_eob_run(&mut eob_run, &mut emit);
+            }
+
+            let mut run = 0;
+            for &coef in band {
+                if coef == 0 {
+                    run += 1;
+                    continue;
+                }
+                while run >= 16 {
+                    emit(1, table, 0xF0, 0, 0);
+                    run -= 16;
+                }
+                let (size, bits) = magnitude(coef);
+                emit(1, table, run << 4 | size, bits, size);
+                run = 0;
+            }
+            if run > 0 {
+                if scan.start > 0 {
+                    eob_run = 1;
+                } else {
+                    emit(1, table, 0x00, 0, 0);
+                }
+            }
+        }
+        flush_eob_run(&mut eob_run, &mut emit);
+    }
+
+    /// Lists the blocks of a scan in the order they are coded.
+    fn scan_blocks(&self, scan: &[usize]) -> Vec<(usize, &[i16; 64])> {
+        let mut blocks = Vec::new();
+        if let [c] = *scan {
+            // Non-interleaved scans only cover the blocks that overlap the image.
+            let component = &self.components[c];
+            for y in 0..component.blocks_y {
+                for x in 0..component.blocks_x {
+                    blocks.push((c, &component.blocks[y * component.stride + x]));
+                }
+            }
+            return blocks;
+        }
+
+        for mcu_y in 0..self.mcus_y {
+            for mcu_x in 0..self.mcus_x {
+                for &c in scan {
+                    let component = &self.components[c];
+                    for v in 0..component.v {
+                        for h in 0..component.h {
+                            let x = mcu_x * component.h + h;
+                            let y = mcu_y * component.v + v;
+                            blocks.push((c, &component.blocks[y * component.stride + x]));
+                        }
+                    }
+                }
+            }
+        }
+        blocks
+    }
+}
+
+/// Copies one channel out of interleaved 8-bit pixels, replicating edge pixels into the padding.
+fn pad_plane(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    padded_w: usize,
+    padded_h: usize,
+    sample: impl Fn(&[u8]) -> f32,
+    channels: usize,
+) -> Vec<f32> {
+    let mut plane = Vec::with_capacity(padded_w * padded_h);
+    for y in 0..padded_h {
+        let row = y.min(height - 1) * width;
+        for x in 0..padded_w {
+            let i = (row + x.min(width - 1)) * channels;
+            plane.push(sample(&pixels[i..i + channels]));
+        }
+    }
+    plane
+}
+
+/// Averages `fx` x `fy` boxes of samples into one.
+fn downsample(plane: &[f32], width: usize, fx: usize, fy: usize) -> Vec<f32> {
+    if fx == 1 && fy == 1 {
+        return plane.to_vec();
+    }
+    let height = plane.len() / width;
+    let scale = 1.0 / (fx * fy) as f32;
+    let mut out = Vec::with_capacity(plane.len() / (fx * fy));
+    for y in (0..height).step_by(fy) {
+        for x in (0..width).step_by(fx) {
+            let sum: f32 = (0..fy)
+                .flat_map(|dy| (0..fx).map(move |dx| plane[(y + dy) * width + x + dx]))
+                .sum();
+            out.push(sum * scale);
+        }
+    }
+    out
+}
+
+/// Applies the forward DCT to every 8x8 block of a plane.
+fn transform_plane(plane: &[f32], width: usize, height: usize) -> Vec<[f32; 64]> {
+    // DCT basis: COS[u][x] = C(u) / 2 * cos((2x + 1) * u * pi / 16)
+    let cos: [[f32; 8]; 8] = std::array::from_fn(|u| {
+        let c = if u == 0 { 1.0 / 2f32.sqrt() } else { 1.0 };
+        std::array::from_fn(|x| {
+            c / 2.0 * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos()
+        })
+    });
+
+    let mut blocks = Vec::with_capacity(width * height / 64);
+    for by in (0..height).step_by(8) {
+        for bx in (0..width).step_by(8) {
+            let mut rows = [[0f32; 8]; 8];
+            for (y, row) in rows.iter_mut().enumerate() {
+                let samples = &plane[(by + y) * width + bx..][..8];
+                for (u, out) in row.iter_mut().enumerate() {
+                    *out = (0..8).map(|x| (samples[x] - 128.0) * cos[u][x]).sum();
+                }
+            }
+            let mut block = [0f32; 64];
+            for (i, &natural) in ZIGZAG.iter().enumerate() {
+                let (v, u) = (natural / 8, natural % 8);
+                block[i] = (0..8).map(|y| rows[y][u] * cos[v][y]).sum();
+            }
+            blocks.push(block);
+        }
+    }
+    blocks
+}
+
+/// Returns the size category of a coefficient and its extra bits.
+fn magnitude(value: i16) -> (u8, u32) {
+    let size = (16 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 {
+        (value as i32 - 1) as u32 & ((1 << size) - 1)
+    } else {
+        value as u32
+    };
+    (size, bits)
+}
+
+/// Builds an optimal length-limited Huffman table from symbol frequencies (JPEG spec, Annex K.2).
+///
+/// Returns the code length counts (`BITS`) and the symbols ordered by code length (`HUFFVAL`).
+fn optimal_table(frequencies: &[u32; 257]) -> ([u8; 16], Vec<u8>) {
+    let mut freq: Vec<u64> = frequencies.iter().map(|&f| f as u64).collect();
+    // Reserve one code point so that no real code consists of all 1 bits.
+    freq[256] = 1;
+    let mut code_size = [0usize; 257];
+    let mut others = [usize::MAX; 257];
+
+    loop {
+        // The two least frequent symbols, preferring higher indices on ties
+        let smallest = |exclude: Option<usize>| {
+            (0..257)
+                .filter(|&i| freq[i] > 0 && Some(i) != exclude)
+                .min_by_key(|&i| (freq[i], usize::MAX - i))
+        };
+        let Some(c1) = smallest(None) else { break };
+        let Some(c2) = smallest(Some(c1)) else { break };
+
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+
+        let mut c = c1;
+        code_size[c] += 1;
+        while others[c] != usize::MAX {
+            c = others[c];
+            code_size[c] += 1;
+        }
+        others[c] = c2;
+        let mut c = c2;
+        code_size[c] += 1;
+        while others[c] != usize::MAX {
+            c = others[c];
+            code_size[c] += 1;
+        }
+    }
+
+    let mut bits = [0usize; 33];
+    for &size in code_size.iter().filter(|&&s| s > 0) {
+        bits[size] += 1;
+    }
+    // Limit code lengths to 16 bits
+    for i in (17..=32).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    // Drop the reserved code point, which always has the longest code
+    let longest = (1..=16).rev().find(|&i| bits[i] > 0).unwrap();
+    bits[longest] -= 1;
+
+    let mut values = Vec::new();
+    for size in 1..=32 {
+        values.extend((0..256).filter(|&s| code_size[s] == size).map(|s| s as u8));
+    }
+    (std::array::from_fn(|i| bits[i + 1] as u8), values)
+}
+
+/// Assigns canonical codes to a table, as `(code, length)` indexed by symbol.
+fn code_table(bits: &[u8; 16], values: &[u8]) -> [(u16, u8); 256] {
+    let mut table = [(0, 0); 256];
+    let mut code = 0u16;
+    let mut symbols = values.iter();
+    for (i, &count) in bits.iter().enumerate() {
+        for &symbol in symbols.by_ref().take(count as usize) {
+            table[symbol as usize] = (code, i as u8 + 1);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    table
+}
+
+/// Largest payload of a marker segment, after the two length bytes.
+const MAX_SEGMENT_PAYLOAD: usize = u16::MAX as usize - 2;
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn write_jfif(out: &mut Vec<u8>) {
+    write_segment(out, 0xE0, b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00");
+}
+
+fn write_exif(out: &mut Vec<u8>, exif: &[u8]) {
+    const HEADER: &[u8] = b"Exif\0\0";
+    if HEADER.len() + exif.len() <= MAX_SEGMENT_PAYLOAD {
+        write_segment(out, 0xE1, &[HEADER, exif].concat());
+    }
+}
+
+/// Writes the profile split over as many APP2 segments as needed (at most 255), each with the
+/// `ICC_PROFILE` header, its sequence number and the total count.
+fn write_icc_profile(out: &mut Vec<u8>, icc_profile: &[u8]) {
+    const HEADER: &[u8] = b"ICC_PROFILE\0";
+    let chunks: Vec<_> = icc_profile
+        .chunks(MAX_SEGMENT_PAYLOAD - HEADER.len() - 2)
+        .collect();
+    let Ok(count) = u8::try_from(chunks.len()) else {
+        return;
+    };
+    for (i, chunk) in chunks.iter().enumerate() {
+        write_segment(out, 0xE2, &[HEADER, &[i as u8 + 1, count], chunk].concat());
+    }
+}
+
+fn write_quant_tables(out: &mut Vec<u8>, tables: &[[u16; 64]]) {
+    let mut payload = Vec::new();
+    for (id, table) in tables.iter().enumerate() {
+        payload.push(id as u8);
+        payload.extend(ZIGZAG.iter().map(|&natural| table[natural] as u8));
+    }
+    write_segment(out, 0xDB, &payload);
+}
+
+fn write_frame_header(
+    out: &mut Vec<u8>,
+    frame: &Frame<i16>,
+    width: usize,
+    height: usize,
+    progressive: bool,
+) {
+    let mut payload = vec![8];
+    payload.extend_from_slice(&(height as u16).to_be_bytes());
+    payload.extend_from_slice(&(width as u16).to_be_bytes());
+    payload.push(frame.components.len() as u8);
+    for c in &frame.components {
+        payload.extend_from_slice(&[c.id, (c.h << 4 | c.v) as u8, c.table as u8]);
+    }
+    write_segment(out, if progressive { 0xC2 } else { 0xC0 }, &payload);
+}
+
+/// Writes entropy-coded data, stuffing a zero byte after every 0xFF.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self {
+            out,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, bits: u32, len: u8) {
+        if len == 0 {
+            return;
+        }
+        self.buffer = self.buffer << len | (bits & ((1 << len) - 1));
+        self.count += len;
+        while self.count >= 8 {
+            self.count -= 8;
+            let byte = (self.buffer >> self.count) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0);
+            }
+        }
+        self.buffer &= (1 << self.count) - 1;
+    }
+
+    /// Pads the last byte with 1 bits.
+    fn flush(&mut self) {
+        if self.count > 0 {
+            let pad = 8 - self.count;
+            self.write((1 << pad) - 1, pad);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(
+        image: &DynamicImage,
+        quality: u8,
+        subsampling: Subsampling,
+        progressive: bool,
+        metadata: &Metadata,
+    ) -> Result<Vec<u8>, ImageError> {
+        Ok(Transformed::new(image, subsampling)?.encode(quality, progressive, metadata))
+    }
+
+    fn test_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(37, 21, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 12) as u8, ((x + y) * 4) as u8])
+        }))
+    }
+
+    fn max_error(a: &DynamicImage, b: &DynamicImage) -> u8 {
+        a.to_rgb8()
+            .as_raw()
+            .iter()
+            .zip(b.to_rgb8().as_raw())
+            .map(|(x, y)| x.abs_diff(*y))
+            .max()
+            .unwrap()
+    }
+
+    // Every subsampling mode decodes back to the original size and close to the original pixels.
+    #[test]
+    fn test_roundtrip_all_subsampling_modes() {
+        let img = test_image();
+        for mode in [Subsampling::S444, Subsampling::S422, Subsampling::S420] {
+            let data = encode(&img, 95, mode, false, &Metadata::default()).unwrap();
+
+            let decoded = image::load_from_memory(&data).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (37, 21), "{mode:?}");
+            assert!(max_error(&img, &decoded) < 40, "{mode:?}");
+        }
+    }
+
+    // Subsampling trades chroma resolution for size.
+    #[test]
+    fn test_subsampling_shrinks_output() {
+        let img = test_image();
+
+        let full = encode(&img, 90, Subsampling::S444, false, &Metadata::default()).unwrap();
+        let half = encode(&img, 90, Subsampling::S420, false, &Metadata::default()).unwrap();
+
+        assert!(half.len() < full.len());
+    }
+
+    // Grayscale images are written with a single component.
+    #[test]
+    fn test_grayscale_roundtrip() {
+        let img = DynamicImage::ImageLuma8(image::GrayImage::from_fn(20, 20, |x, y| {
+            image::Luma([(x * 10 + y) as u8])
+        }));
+
+        let data = encode(&img, 90, Subsampling::S420, false, &Metadata::default()).unwrap();
+
+        let decoded = image::load_from_memory(&data).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+        assert!(max_error(&img, &decoded) < 10);
+    }
+
+    // One transform serves several qualities, with the same output as transforming each time.
+    #[test]
+    fn test_transform_is_reused_across_qualities() {
+        let img = test_image();
+        let transformed = Transformed::new(&img, Subsampling::S420).unwrap();
+
+        for quality in [40, 75, 95] {
+            let reused = transformed.encode(quality, false, &Metadata::default());
+            let fresh = encode(
+                &img,
+                quality,
+                Subsampling::S420,
+                false,
+                &Metadata::default(),
+            );
+
+            assert_eq!(reused, fresh.unwrap(), "quality {quality}");
+        }
+    }
+
+    // Progressive output decodes to the same pixels as the baseline output.
+    #[test]
+    fn test_progressive_matches_baseline() {
+        let img = test_image();
+        for mode in [Subsampling::S444, Subsampling::S420] {
+            let baseline = encode(&img, 90, mode, false, &Metadata::default()).unwrap();
+            let progressive = encode(&img, 90, mode, true, &Metadata::default()).unwrap();
+
+            assert_eq!(&progressive[..2], &[0xFF, 0xD8]);
+            let baseline = image::load_from_memory(&baseline).unwrap();
+            let progressive = image::load_from_memory(&progressive).unwrap();
+            assert_eq!(max_error(&baseline, &progressive), 0, "{mode:?}");
+        }
+    }
+
+    // A second decoder, in strict mode, reads every variant and agrees with `image`'s.
+    #[test]
+    fn test_second_decoder() {
+        use zune_jpeg::zune_core::options::DecoderOptions;
+
+        let img = test_image();
+        for mode in [Subsampling::S444, Subsampling::S422, Subsampling::S420] {
+            for progressive in [false, true] {
+                let data = encode(&img, 90, mode, progressive, &Metadata::default()).unwrap();
+
+                let options = DecoderOptions::default().set_strict_mode(true);
+                let pixels = zune_jpeg::JpegDecoder::new_with_options(data.as_slice(), options)
+                    .decode()
+                    .unwrap_or_else(|e| panic!("{mode:?} progressive={progressive}: {e:?}"));
+                let decoded = image::RgbImage::from_raw(37, 21, pixels).unwrap().into();
+                let reference = image::load_from_memory(&data).unwrap();
+                assert!(
+                    max_error(&img, &decoded) < 40,
+                    "{mode:?} progressive={progressive}"
+                );
+                assert!(
+                    max_error(&reference, &decoded) <= 4,
+                    "{mode:?} progressive={progressive}"
+                );
+            }
+        }
+    }
+}
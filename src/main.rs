@@ -1,63 +1,560 @@
-use clap::Parser;
-use image::{DynamicImage, ImageError, ImageReader};
+mod config;
+mod jpeg;
+mod metadata;
+mod palette;
+mod preset;
+mod size;
+mod spec;
+mod strategy;
+mod template;
+mod tonemap;
+mod walk;
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, parser::ValueSource};
+use image::{
+    DynamicImage, GenericImageView, ImageDecoder, ImageError, ImageFormat, ImageReader,
+    metadata::Orientation,
+};
+use metadata::{Metadata, OrientationMode};
+use preset::Preset;
+use size::{HumanBytes, Size};
 use std::{
+    ffi::OsString,
     fs::File,
-    io::{self, BufReader, Read, Write},
+    io::{self, Cursor, Read, Write},
     path::{Path, PathBuf},
 };
+use strategy::{Encoded, Strategy};
+use template::NameTemplate;
 
 /// Encode images to shrink them, if possible, by re-saving them with different encoders and settings.
 #[derive(Debug, Parser)]
+#[clap(args_override_self = true)]
 struct App {
-    /// The target size in bytes
-    #[clap(short = 's', long, default_value = "15728640")]
-    target_size: usize,
+    /// Read default options from this file instead of `$XDG_CONFIG_HOME/reenc-image/config.toml`
+    #[clap(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Also apply the options of this `[profile.NAME]` section of the config file
+    #[clap(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Print the effective settings, merged from the config file and the command line, and exit
+    #[clap(long)]
+    print_config: bool,
+
+    /// Use the size, dimension and format limits of a site; other options override it
+    #[clap(long, value_name = "NAME", value_parser = clap::builder::PossibleValuesParser::new(preset::possible_values()))]
+    preset: Option<String>,
+
+    /// The target size: in bytes, with a unit (`15MiB`, `8MB`, `500k`), or relative to each
+    /// input's size (`50%`) [default: 15MiB, or the preset's]
+    #[clap(short = 's', long)]
+    target_size: Option<Size>,
+
+    /// Headroom to keep below the target size, with a unit or as a percentage of the target
+    /// (e.g. `100KiB`, `5%`)
+    #[clap(long)]
+    margin: Option<Size>,
+
+    /// The strategies to try, in order, e.g. `png:best,webp:lossless,jpeg:95,jpeg:85`.
+    /// Parameters left out are taken from the other options
+    #[clap(long, value_name = "CHAIN")]
+    strategy: Option<String>,
+
+    /// Only write these formats (e.g. `jpeg,png,webp`), dropping the strategies that produce
+    /// others. Inputs in other formats are converted even if they are already small enough
+    #[clap(long, value_name = "FORMATS", value_delimiter = ',', value_parser = parse_format)]
+    formats: Vec<ImageFormat>,
+
+    /// The lowest JPEG quality the quality search may go down to (1-100)
+    #[clap(short = 'q', long, default_value = "70", value_parser = clap::value_parser!(u8).range(1..=100))]
+    min_quality: u8,
+
+    /// Encode JPEG with this chroma subsampling instead of the default encoder's 4:4:4
+    #[clap(long, value_name = "MODE")]
+    jpeg_subsampling: Option<jpeg::Subsampling>,
+
+    /// Write progressive JPEG for every JPEG step
+    #[clap(long)]
+    progressive: bool,
+
+    /// AVIF encoder speed, from 1 (slowest, smallest) to 10 (fastest)
+    #[cfg(feature = "avif")]
+    #[clap(long, default_value = "6", value_parser = clap::value_parser!(u8).range(1..=10))]
+    avif_speed: u8,
+
+    /// The highest AVIF quality the quality search starts from (1-100)
+    #[cfg(feature = "avif")]
+    #[clap(long, default_value = "90", value_parser = clap::value_parser!(u8).range(1..=100))]
+    avif_quality: u8,
+
+    /// Write the outputs under this directory instead of next to the inputs, keeping the inputs'
+    /// directory structure below their common ancestor
+    #[clap(short = 'o', long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Output filename, with the placeholders {stem}, {ext}, {format}, {quality}, {width},
+    /// {height}, {target} (in bytes) and {date} (UTC, YYYY-MM-DD)
+    #[clap(long, value_name = "TEMPLATE", default_value = template::DEFAULT)]
+    name_template: NameTemplate,
+
+    /// Remove the location (the EXIF GPS tags) from the outputs, keeping the rest of the EXIF
+    /// data. Inputs with a location are re-encoded even if they are already small enough
+    #[clap(long)]
+    strip_gps: bool,
+
+    /// How to handle the EXIF orientation: rotate the pixels upright, or keep them as stored and
+    /// keep the tag
+    #[clap(long, value_name = "MODE", value_enum, default_value_t = OrientationMode::Apply)]
+    orientation: OrientationMode,
+
+    /// Tone mapping curve for HDR (floating-point) inputs, such as OpenEXR and Radiance HDR
+    #[clap(long, value_name = "OPERATOR", value_enum, default_value_t = tonemap::Operator::Aces)]
+    tone_map: tonemap::Operator,
+
+    /// Exposure adjustment for HDR inputs, in stops (e.g. `-1` halves the brightness)
+    #[clap(long, value_name = "STOPS", default_value = "0", allow_negative_numbers = true, value_parser = tonemap::parse_exposure)]
+    exposure: f32,
+
+    /// The brightness in HDR inputs (after --exposure, 1.0 being the nominal white) that becomes
+    /// full white; brighter areas clip [default: depends on --tone-map]
+    #[clap(long, value_name = "VALUE", value_parser = tonemap::parse_white_point)]
+    white_point: Option<f32>,
+
+    /// Replace each input with its re-encoded version. If the format changes, the input is
+    /// replaced by a file with the new extension
+    #[clap(long, conflicts_with_all = ["output_dir", "name_template"])]
+    in_place: bool,
+
+    /// With --in-place, keep the original under its name plus this suffix
+    #[clap(long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true, default_missing_value = ".bak", requires = "in_place")]
+    backup: Option<String>,
+
+    /// Give the outputs the modification and access times of their inputs
+    #[clap(long, conflicts_with = "stdout")]
+    preserve_times: bool,
+
+    /// Give the outputs the permissions of their inputs
+    #[clap(long, conflicts_with = "stdout")]
+    preserve_mode: bool,
+
+    /// Write the encoded image to stdout (or the input unchanged, if it is already small enough);
+    /// progress goes to stderr. Takes a single input, which may be `-` for stdin
+    #[clap(long, conflicts_with_all = ["output_dir", "name_template", "in_place"])]
+    stdout: bool,
 
     /// Overwrite existing files with the same name as the encoded ones (if they exist)
     #[clap(short = 'f', long)]
     force: bool,
 
+    /// How hard to search for the smallest lossless PNG: 0 uses the adaptive filter only, 1 also tries
+    /// the common fixed filters, 2 tries every filter
+    #[clap(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=strategy::MAX_PNG_EFFORT as i64))]
+    png_effort: u8,
+
+    /// Also try a palette-quantized PNG with at most this many colors (2-256), before the lossy strategies
+    #[clap(short = 'p', long, value_name = "COLORS", value_parser = clap::value_parser!(u16).range(2..=256))]
+    palette: Option<u16>,
+
+    /// Apply Floyd-Steinberg dithering to the palette-quantized PNG
+    #[clap(long, requires = "palette")]
+    dither: bool,
+
+    /// Shrink images wider than this, keeping the aspect ratio
+    #[clap(long, value_name = "PIXELS")]
+    max_width: Option<u32>,
+
+    /// Shrink images taller than this, keeping the aspect ratio
+    #[clap(long, value_name = "PIXELS")]
+    max_height: Option<u32>,
+
+    /// Downscale the image step by step (2160p, 1440p, 1080p, ...) when no strategy fits
+    #[clap(short = 'd', long)]
+    downscale: bool,
+
     /// Wait for user input before exiting (useful when launched from a file manager)
     #[clap(short = 'w', long, default_value_t = cfg!(windows), action = clap::ArgAction::Set)]
     wait: bool,
 
-    /// The input image files to be re-encoded
-    #[clap(required = true)]
+    /// Only take the files matching these glob patterns from directory inputs, instead of every
+    /// image file. Patterns without a `/` match the filename, others the path below the directory
+    #[clap(long, value_name = "GLOB", value_delimiter = ',')]
+    include: Vec<String>,
+
+    /// Leave out the files and directories matching these glob patterns when walking directories
+    #[clap(long, value_name = "GLOB", value_delimiter = ',')]
+    exclude: Vec<String>,
+
+    /// How many levels of directory inputs to walk; 1 only takes the files directly inside
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_depth: Option<u32>,
+
+    /// The input image files to be re-encoded; `-` reads one from stdin (with --stdout).
+    /// Directories are walked recursively, skipping earlier outputs (`*-reenc.*`)
+    #[clap(required_unless_present = "print_config")]
     images: Vec<PathBuf>,
 }
 
+/// Input path that stands for stdin.
+const STDIN_PATH: &str = "-";
+
+/// Target size used when neither `-s` nor a preset gives one.
+const DEFAULT_TARGET_SIZE: Size = Size::Bytes(15 << 20);
+
 fn main() {
-    let app = App::parse();
-    println!("Target size: {} bytes", app.target_size);
+    let (app, matches) = parse_args();
+    let preset = app.preset.as_deref().and_then(preset::find);
+    let mut strategies = build_strategies(&app, preset).unwrap_or_else(|e| {
+        let message = match &app.strategy {
+            Some(_) => format!("--strategy: {e}"),
+            None => e.to_string(),
+        };
+        App::command()
+            .error(clap::error::ErrorKind::ValueValidation, message)
+            .exit()
+    });
+    if !app.formats.is_empty() {
+        strategies.retain(|s| app.formats.contains(&s.format()));
+        if strategies.is_empty() {
+            App::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--formats: none of the strategies writes one of the given formats",
+                )
+                .exit()
+        }
+    }
+    if !app.name_template.has_stem()
+        && (app.images.len() > 1 || app.images.iter().any(|p| p.is_dir()))
+    {
+        App::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                "--name-template: without {stem}, every input would be written to the same file",
+            )
+            .exit()
+    }
+    let options = EncodeOptions {
+        target_size: app
+            .target_size
+            .or(preset.map(|p| p.target_size))
+            .unwrap_or(DEFAULT_TARGET_SIZE),
+        margin: app.margin,
+        max_width: app.max_width.or(preset.and_then(|p| p.max_width)),
+        max_height: app.max_height.or(preset.and_then(|p| p.max_height)),
+        downscale: app.downscale,
+        formats: (!app.formats.is_empty()).then(|| app.formats.clone()),
+        output_dir: app.output_dir.clone().map(|dir| OutputDir {
+            dir,
+            base: common_ancestor(&app.images),
+        }),
+        name_template: app.name_template.clone(),
+        strip_gps: app.strip_gps,
+        orientation: app.orientation,
+        tone_map: tonemap::ToneMap {
+            operator: app.tone_map,
+            exposure: app.exposure,
+            white_point: app.white_point,
+        },
+        in_place: app.in_place,
+        backup_suffix: app.backup.clone(),
+        preserve: Preserve {
+            times: app.preserve_times,
+            mode: app.preserve_mode,
+        },
+        strategies,
+    };
+    if app.print_config {
+        print_config(&app, &matches, &options);
+        return;
+    }
+    let filter = walk::Filter {
+        include: app.include.clone(),
+        exclude: app.exclude.clone(),
+        max_depth: app.max_depth,
+    };
+    let images = walk::expand_inputs(&app.images, &filter).unwrap_or_else(|e| {
+        App::command()
+            .error(
+                clap::error::ErrorKind::Io,
+                format!("failed to read a directory: {e}"),
+            )
+            .exit()
+    });
+    let reads_stdin = images.iter().any(|image| image == Path::new(STDIN_PATH));
+    if app.stdout && images.len() != 1 {
+        App::command()
+            .error(
+                clap::error::ErrorKind::WrongNumberOfValues,
+                "--stdout takes exactly one input",
+            )
+            .exit()
+    }
+    if reads_stdin && !app.stdout {
+        App::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "reading an image from stdin (`-`) requires --stdout",
+            )
+            .exit()
+    }
 
-    for image in &app.images {
-        print!("Re-encoding {} ", image.display());
-        io::stdout().flush().unwrap();
+    // With --stdout, stdout carries the image, so the progress goes to stderr
+    let mut log: Box<dyn Write> = if app.stdout {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    macro_rules! log {
+        ($($arg:tt)*) => {
+            let _ = writeln!(log, $($arg)*);
+        };
+    }
+
+    if let Some(preset) = preset {
+        log!("Preset: {}", preset.description);
+    }
+    let margin = app
+        .margin
+        .map(|m| format!(", after a {m} margin"))
+        .unwrap_or_default();
+    match options.target_size {
+        Size::Bytes(_) => {
+            let target_size = options.target_size_for(0);
+            log!(
+                "Target size: {target_size} bytes ({}{margin})",
+                HumanBytes(target_size as u64)
+            );
+        }
+        Size::Percent(percent) => {
+            log!("Target size: {percent}% of each input{margin}");
+        }
+    }
+    let chain: Vec<_> = options.strategies.iter().map(|s| s.to_string()).collect();
+    log!("Strategies: {}", chain.join(", "));
 
-        match re_encode_image(image, app.target_size, app.force) {
+    let mut failed = false;
+    for image in &images {
+        let _ = write!(log, "Re-encoding {} ", image.display());
+        let _ = log.flush();
+
+        let result = if app.stdout {
+            re_encode_to_writer(image, &options, &mut io::stdout().lock())
+        } else {
+            re_encode_image(image, &options, app.force)
+        };
+        match result {
             Ok(EncodeOutcome::Encoded {
                 original_size,
                 new_size,
                 new_path,
+                strategy,
+                quality,
+                downscaled_to,
+                dropped_icc_profile,
             }) => {
-                println!(
-                    " ({original_size} bytes) -> {} ({new_size} bytes)",
+                let quality = quality.map(|q| format!(" q = {q}")).unwrap_or_default();
+                let downscaled = downscaled_to
+                    .map(|(w, h)| format!(", downscaled to {w}x{h}"))
+                    .unwrap_or_default();
+                log!(
+                    " ({original_size} bytes) -> {} ({new_size} bytes, {strategy}{quality}{downscaled})",
                     new_path.display()
                 );
+                if dropped_icc_profile {
+                    log!(
+                        "   warning: {strategy} cannot carry the input's ICC profile; colors may shift"
+                    );
+                }
             }
             Ok(EncodeOutcome::Skipped { original_size }) => {
-                println!(" ({original_size} bytes) -> (skipped, already smaller than target)");
+                log!(" ({original_size} bytes) -> (skipped, already smaller than target)");
             }
             Err(e) => {
-                println!("-> *** error: {e}");
+                failed = true;
+                log!("-> *** error: {e}");
             }
         }
     }
 
-    if app.wait {
+    if app.wait && !reads_stdin {
         // Wait for user input before exiting, so they can see the results
         let _ = io::stdin().read_exact(&mut [0]);
     }
+    if failed && app.stdout {
+        // Nothing usable was written; let the pipeline know
+        std::process::exit(1);
+    }
+}
+
+/// Parses the command line, with the options from the config file (and `--profile`) filling in
+/// the ones it does not give.
+fn parse_args() -> (App, ArgMatches) {
+    let args: Vec<OsString> = std::env::args_os().collect();
+
+    // `--config` and `--profile` are needed before the real parse; errors are reported by it
+    let early = App::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok();
+    let config_path = early
+        .as_ref()
+        .and_then(|m| m.get_one::<PathBuf>("config").cloned());
+    let profile = early
+        .as_ref()
+        .and_then(|m| m.get_one::<String>("profile").cloned());
+    let given: Vec<&str> = early
+        .iter()
+        .flat_map(|m| {
+            m.ids()
+                .filter(|id| m.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+                .map(|id| id.as_str())
+        })
+        .collect();
+
+    let config = match config_path.clone().or_else(config::default_path) {
+        Some(path) => config::Config::load(&path, config_path.is_some()),
+        None => Ok(config::Config::default()),
+    };
+    let mut config_args = config
+        .and_then(|c| c.to_args(&App::command(), profile.as_deref(), &given))
+        .unwrap_or_else(|e| {
+            App::command()
+                .error(clap::error::ErrorKind::InvalidValue, format!("config: {e}"))
+                .exit()
+        });
+
+    // Options from the file that need another one (`dither` needs `palette`) are only used when
+    // that one is given too
+    let missing = missing_args(&args, &config_args);
+    if !missing.is_empty() {
+        config_args.retain(|arg| {
+            missing_args(&args, std::slice::from_ref(arg))
+                .iter()
+                .all(|m| !missing.contains(m))
+        });
+    }
+
+    let matches = App::command()
+        .try_get_matches_from(merge_args(&args, &config_args))
+        .unwrap_or_else(|e| {
+            // Report mistakes on the command line without the options from the config file
+            match App::command().try_get_matches_from(&args) {
+                Err(cli_error) => cli_error.exit(),
+                Ok(_) => e.exit(),
+            }
+        });
+    let app = App::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    (app, matches)
+}
+
+/// The command line with the options from the config file inserted before the given ones.
+fn merge_args<'a>(
+    args: &'a [OsString],
+    config_args: &'a [OsString],
+) -> impl Iterator<Item = &'a OsString> {
+    args.iter()
+        .take(1)
+        .chain(config_args)
+        .chain(args.iter().skip(1))
+}
+
+/// The options the command line is missing because other ones require them, once `config_args`
+/// are added to it.
+fn missing_args(args: &[OsString], config_args: &[OsString]) -> Vec<String> {
+    use clap::error::{ContextKind, ContextValue, ErrorKind};
+
+    match App::command().try_get_matches_from(merge_args(args, config_args)) {
+        Err(e) if e.kind() == ErrorKind::MissingRequiredArgument => {
+            match e.get(ContextKind::InvalidArg) {
+                Some(ContextValue::Strings(missing)) => missing.clone(),
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Prints the effective settings in config file syntax, with the preset already applied to the
+/// target size, dimensions and strategies.
+fn print_config(app: &App, matches: &ArgMatches, options: &EncodeOptions) {
+    if let Some(path) = app.config.clone().or_else(config::default_path) {
+        println!("# config: {}", path.display());
+    }
+    if let Some(profile) = &app.profile {
+        println!("# profile: {profile}");
+    }
+    for arg in App::command().get_arguments() {
+        let Some(key) = arg.get_long() else {
+            continue;
+        };
+        if config::CLI_ONLY.contains(&key) {
+            continue;
+        }
+        let value = match key {
+            "target-size" => Some(match options.target_size {
+                Size::Bytes(bytes) => bytes.to_string(),
+                Size::Percent(percent) => format!("{percent}%"),
+            }),
+            "max-width" => options.max_width.map(|w| w.to_string()),
+            "max-height" => options.max_height.map(|h| h.to_string()),
+            "strategy" => Some(
+                options
+                    .strategies
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            _ => matches.get_raw(arg.get_id().as_str()).map(|values| {
+                values
+                    .map(|v| v.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }),
+        };
+        if let Some(value) = value {
+            println!("{key} = {}", config::format_value(&value));
+        }
+    }
+}
+
+/// Parses a `--formats` item by its name or file extension (`jpeg`, `jpg`, `png`, ...).
+fn parse_format(name: &str) -> Result<ImageFormat, String> {
+    ImageFormat::from_extension(name.trim())
+        .filter(|format| format.writing_enabled())
+        .ok_or_else(|| format!("unknown or unsupported output format: {name}"))
+}
+
+/// Builds the strategy chain from `--strategy` or the preset, or the default chain if neither
+/// gives one.
+fn build_strategies(
+    app: &App,
+    preset: Option<&Preset>,
+) -> Result<Vec<Box<dyn Strategy>>, spec::SpecError> {
+    let defaults = spec::Defaults {
+        min_quality: app.min_quality,
+        png_effort: app.png_effort,
+        palette_colors: app.palette,
+        dither: app.dither,
+        jpeg_subsampling: app.jpeg_subsampling,
+        progressive: app.progressive,
+        #[cfg(feature = "avif")]
+        avif_speed: app.avif_speed,
+        #[cfg(feature = "avif")]
+        avif_max_quality: app.avif_quality,
+    };
+    match (&app.strategy, preset) {
+        (Some(chain), _) => spec::parse_chain(chain, &defaults),
+        (None, Some(preset)) => {
+            // The preset's chain gets the palette strategy of -p like the default chain does
+            let mut chain = spec::parse_chain(preset.strategies, &defaults)?;
+            spec::add_palette(&mut chain, &defaults);
+            Ok(chain)
+        }
+        (None, None) => spec::default_chain(&defaults),
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -65,11 +562,158 @@ enum Error {
     #[error("Failed to process image: {0}")]
     Image(#[from] ImageError),
 
+    #[error("Failed to encode PNG: {0}")]
+    Png(#[from] png::EncodingError),
+
     #[error("io error: {0}")]
     Io(#[from] io::Error),
 
     #[error("Image size exceeds target size after encoded")]
     ImageSizeExceedsTarget,
+
+    #[error("The name template gives an invalid filename: {0:?}")]
+    InvalidOutputName(String),
+
+    #[error("The output would overwrite the input: {}", .0.display())]
+    OutputIsInput(PathBuf),
+}
+
+/// Settings for re-encoding a single image.
+#[derive(Debug)]
+struct EncodeOptions {
+    target_size: Size,
+    /// Headroom subtracted from the target size
+    margin: Option<Size>,
+    /// Images larger than these are shrunk before encoding
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    /// Whether to downscale the image when no strategy fits
+    downscale: bool,
+    /// Formats the output may be in; inputs in other formats are never skipped
+    formats: Option<Vec<ImageFormat>>,
+    /// Where to write the outputs, if not next to the inputs
+    output_dir: Option<OutputDir>,
+    name_template: NameTemplate,
+    /// Whether to remove the location from the EXIF data
+    strip_gps: bool,
+    orientation: OrientationMode,
+    /// How to bring HDR inputs down to 8-bit sRGB
+    tone_map: tonemap::ToneMap,
+    /// Whether to replace the inputs instead of writing new files
+    in_place: bool,
+    /// Suffix of the backup kept of each replaced input
+    backup_suffix: Option<String>,
+    /// File attributes to copy from the inputs to the outputs
+    preserve: Preserve,
+    /// Strategies to try, in order
+    strategies: Vec<Box<dyn Strategy>>,
+}
+
+impl EncodeOptions {
+    /// The size the output has to stay below, for an input of `original_size` bytes.
+    fn target_size_for(&self, original_size: u64) -> usize {
+        let target_size = self.target_size.resolve(original_size);
+        let margin = self.margin.map_or(0, |m| m.resolve(target_size));
+        usize::try_from(target_size.saturating_sub(margin)).unwrap_or(usize::MAX)
+    }
+}
+
+/// A separate output directory. An input at `base/a/b.png` is written to `dir/a/`.
+#[derive(Debug)]
+struct OutputDir {
+    dir: PathBuf,
+    base: PathBuf,
+}
+
+impl OutputDir {
+    /// The directory the output for `image_path` goes to.
+    fn dir_for(&self, image_path: &Path) -> io::Result<PathBuf> {
+        let parent = normalized_absolute(image_path)?
+            .parent()
+            .map(Path::to_owned)
+            .unwrap_or_default();
+        let relative: PathBuf = match parent.strip_prefix(&self.base) {
+            Ok(relative) => relative.to_owned(),
+            // Not below the base (e.g. another drive); keep the whole path
+            Err(_) => parent
+                .components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .collect(),
+        };
+        if relative
+            .components()
+            .any(|c| c == std::path::Component::ParentDir)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside the output directory", relative.display()),
+            ));
+        }
+        Ok(self.dir.join(relative))
+    }
+}
+
+/// Which attributes of the input file the output gets.
+#[derive(Debug, Default)]
+struct Preserve {
+    times: bool,
+    mode: bool,
+}
+
+impl Preserve {
+    /// Copies the attributes from `source` to `file`, after it has been written.
+    fn apply(&self, file: &File, source: &std::fs::Metadata) -> io::Result<()> {
+        if self.times {
+            let mut times = std::fs::FileTimes::new().set_modified(source.modified()?);
+            if let Ok(accessed) = source.accessed() {
+                times = times.set_accessed(accessed);
+            }
+            file.set_times(times)?;
+        }
+        if self.mode {
+            file.set_permissions(source.permissions())?;
+        }
+        Ok(())
+    }
+}
+
+/// `path` made absolute, with `.` and `..` resolved lexically. `std::path::absolute` keeps `..`
+/// on Unix, which would make `a/../b` and `b` look like different directories.
+fn normalized_absolute(path: &Path) -> io::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+/// The deepest directory containing all of `paths`, compared as normalized absolute paths.
+fn common_ancestor(paths: &[PathBuf]) -> PathBuf {
+    let mut ancestor: Option<PathBuf> = None;
+    for path in paths {
+        let Some(parent) = normalized_absolute(path)
+            .ok()
+            .and_then(|p| p.parent().map(Path::to_owned))
+        else {
+            continue;
+        };
+        ancestor = Some(match ancestor {
+            None => parent,
+            Some(ancestor) => ancestor
+                .components()
+                .zip(parent.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    ancestor.unwrap_or_default()
 }
 
 #[derive(Debug)]
@@ -78,112 +722,405 @@ enum EncodeOutcome {
         original_size: u64,
         new_size: u64,
         new_path: PathBuf,
+        /// Name of the strategy that produced the output
+        strategy: &'static str,
+        /// The quality picked by the quality search, if the strategy is lossy
+        quality: Option<u8>,
+        /// The final dimensions, if the image had to be downscaled to fit
+        downscaled_to: Option<(u32, u32)>,
+        /// Whether the input's ICC profile was lost because the output format cannot carry it
+        dropped_icc_profile: bool,
     },
     Skipped {
         original_size: u64,
     },
 }
 
-fn re_encode_image(
-    image_path: &Path,
+/// An image re-encoded in memory, ready to be written out.
+struct Reencoded<'a> {
+    strategy: &'a dyn Strategy,
+    data: Vec<u8>,
+    quality: Option<u8>,
+    /// Final dimensions of the image
+    dimensions: (u32, u32),
+    /// Whether the image had to be shrunk to fit
+    downscaled: bool,
+    /// Whether the input's ICC profile was lost
+    dropped_icc_profile: bool,
     target_size: usize,
-    force_overwrite: bool,
-) -> Result<EncodeOutcome, Error> {
-    let file = File::open(image_path)?;
-    let original_size = file.metadata()?.len();
+}
 
-    if original_size < target_size as u64 {
-        return Ok(EncodeOutcome::Skipped { original_size });
+/// Re-encodes the image file contents in `data`, or returns `None` if they can be used as they
+/// are.
+fn re_encode_data<'a>(
+    data: &[u8],
+    options: &'a EncodeOptions,
+) -> Result<Option<Reencoded<'a>>, Error> {
+    let original_size = data.len() as u64;
+
+    let reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let target_size = options.target_size_for(original_size);
+    let allowed_format = options.formats.as_ref().is_none_or(|formats| {
+        reader
+            .format()
+            .is_some_and(|format| formats.contains(&format))
+    });
+    let mut decoder = reader.into_decoder()?;
+    let mut metadata = Metadata::read(&mut decoder);
+    // A file that is small enough is still rewritten if it has a location to remove
+    let has_unwanted_gps = options.strip_gps && metadata.strip_gps();
+    // HDR inputs are always converted, as they are of little use until tone mapped
+    let is_hdr = matches!(
+        decoder.color_type(),
+        image::ColorType::Rgb32F | image::ColorType::Rgba32F
+    );
+    let orientation = match options.orientation {
+        OrientationMode::Apply => decoder.orientation().unwrap_or(Orientation::NoTransforms),
+        OrientationMode::Tag => Orientation::NoTransforms,
+    };
+
+    let (width, height) = match orientation {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => {
+            let (width, height) = decoder.dimensions();
+            (height, width)
+        }
+        _ => decoder.dimensions(),
+    };
+    let within_dimensions = width <= options.max_width.unwrap_or(u32::MAX)
+        && height <= options.max_height.unwrap_or(u32::MAX);
+    if original_size < target_size as u64
+        && within_dimensions
+        && allowed_format
+        && !has_unwanted_gps
+        && !is_hdr
+    {
+        return Ok(None);
+    }
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    if orientation != Orientation::NoTransforms {
+        image.apply_orientation(orientation);
+        metadata.clear_orientation();
+    }
+    if let Some(mapped) = options.tone_map.apply(&image) {
+        image = mapped;
     }
 
-    let image = ImageReader::new(BufReader::new(file))
-        .with_guessed_format()?
-        .decode()?;
+    let mut downscaled = false;
+    if let Some(smaller) = fit_within(&image, options.max_width, options.max_height) {
+        image = smaller;
+        downscaled = true;
+    }
 
-    for strategy in ENCODE_STRATEGIES {
-        let (encoded_data, extension) = strategy(&image)?;
-        if encoded_data.len() >= target_size {
-            continue;
+    let (strategy, Encoded { data, quality }) = loop {
+        if let Some(encoded) =
+            encode_with_strategies(&image, &metadata, &options.strategies, target_size)?
+        {
+            break encoded;
+        }
+        if !options.downscale {
+            return Err(Error::ImageSizeExceedsTarget);
         }
+        let Some(smaller) = downscale_step(&image) else {
+            return Err(Error::ImageSizeExceedsTarget);
+        };
+        image = smaller;
+        downscaled = true;
+    };
 
-        let mut new_file_name = image_path
-            .file_stem()
-            .expect("image_path must be a file")
-            .to_os_string();
-        new_file_name.push("-reenc");
-        new_file_name.push(extension);
-        let new_path = image_path.with_file_name(new_file_name);
+    Ok(Some(Reencoded {
+        strategy,
+        data,
+        quality,
+        dimensions: image.dimensions(),
+        downscaled,
+        dropped_icc_profile: metadata.icc_profile.is_some() && !strategy.carries_icc_profile(),
+        target_size,
+    }))
+}
 
-        let mut file = if force_overwrite {
-            File::create(&new_path)
-        } else {
-            File::create_new(&new_path)
-        }?;
-        file.write_all(&encoded_data)?;
+fn re_encode_image(
+    image_path: &Path,
+    options: &EncodeOptions,
+    force_overwrite: bool,
+) -> Result<EncodeOutcome, Error> {
+    let data = std::fs::read(image_path)?;
+    let original_size = data.len() as u64;
+    // Taken before an in-place replacement removes the input
+    let source_metadata = std::fs::metadata(image_path)?;
+
+    let Some(Reencoded {
+        strategy,
+        data: encoded_data,
+        quality,
+        dimensions: (width, height),
+        downscaled,
+        dropped_icc_profile,
+        target_size,
+    }) = re_encode_data(&data, options)?
+    else {
+        return Ok(EncodeOutcome::Skipped { original_size });
+    };
+    let downscaled_to = downscaled.then_some((width, height));
 
+    if options.in_place {
+        let new_path = replace_in_place(
+            image_path,
+            strategy.extension(),
+            &encoded_data,
+            options.backup_suffix.as_deref(),
+            |file| options.preserve.apply(file, &source_metadata),
+            force_overwrite,
+        )?;
         return Ok(EncodeOutcome::Encoded {
             original_size,
             new_size: encoded_data.len() as u64,
             new_path,
+            strategy: strategy.name(),
+            quality,
+            downscaled_to,
+            dropped_icc_profile,
         });
     }
 
-    Err(Error::ImageSizeExceedsTarget)
+    let format = format!("{:?}", strategy.format()).to_ascii_lowercase();
+    let new_file_name = options.name_template.render(&template::Fields {
+        stem: &image_path
+            .file_stem()
+            .expect("image_path must be a file")
+            .to_string_lossy(),
+        ext: strategy.extension(),
+        format: &format,
+        quality,
+        width,
+        height,
+        target: target_size,
+    });
+    if matches!(new_file_name.as_str(), "" | "." | "..") {
+        return Err(Error::InvalidOutputName(new_file_name));
+    }
+    let new_path = match &options.output_dir {
+        Some(output_dir) => {
+            let dir = output_dir.dir_for(image_path)?;
+            std::fs::create_dir_all(&dir)?;
+            dir.join(new_file_name)
+        }
+        None => image_path.with_file_name(new_file_name),
+    };
+    // Not even with -f; that is what --in-place is for
+    if is_same_file(&new_path, image_path) {
+        return Err(Error::OutputIsInput(new_path));
+    }
+
+    let mut file = if force_overwrite {
+        File::create(&new_path)
+    } else {
+        File::create_new(&new_path)
+    }?;
+    file.write_all(&encoded_data)?;
+    options.preserve.apply(&file, &source_metadata)?;
+
+    Ok(EncodeOutcome::Encoded {
+        original_size,
+        new_size: encoded_data.len() as u64,
+        new_path,
+        strategy: strategy.name(),
+        quality,
+        downscaled_to,
+        dropped_icc_profile,
+    })
 }
 
-macro_rules! def_encode_fn {
-    ($fn_name:ident, $create_encoder:expr, $extension:expr) => {
-        fn $fn_name(image: &DynamicImage) -> Result<(Vec<u8>, &'static str), Error> {
-            let mut buf = Vec::new();
-            let encoder = $create_encoder(&mut buf);
-            image.write_with_encoder(encoder)?;
-            Ok((buf, $extension))
+/// Re-encodes `input`, or stdin for `-`, and writes the result to `output`. An input that can be
+/// used as it is is copied through unchanged, so that the output is always an image.
+fn re_encode_to_writer(
+    input: &Path,
+    options: &EncodeOptions,
+    output: &mut impl Write,
+) -> Result<EncodeOutcome, Error> {
+    let data = if input == Path::new(STDIN_PATH) {
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data)?;
+        data
+    } else {
+        std::fs::read(input)?
+    };
+    let original_size = data.len() as u64;
+
+    let outcome = match re_encode_data(&data, options)? {
+        Some(reencoded) => {
+            output.write_all(&reencoded.data)?;
+            EncodeOutcome::Encoded {
+                original_size,
+                new_size: reencoded.data.len() as u64,
+                new_path: PathBuf::from("<stdout>"),
+                strategy: reencoded.strategy.name(),
+                quality: reencoded.quality,
+                downscaled_to: reencoded.downscaled.then_some(reencoded.dimensions),
+                dropped_icc_profile: reencoded.dropped_icc_profile,
+            }
+        }
+        None => {
+            output.write_all(&data)?;
+            EncodeOutcome::Skipped { original_size }
         }
     };
+    output.flush()?;
+    Ok(outcome)
 }
 
-def_encode_fn!(
-    encode_to_png,
-    |w| {
-        use image::codecs::png::{CompressionType, FilterType, PngEncoder};
-        PngEncoder::new_with_quality(w, CompressionType::Best, FilterType::Adaptive)
-    },
-    ".png"
-);
-
-def_encode_fn!(
-    encode_to_jpeg_100,
-    |w| {
-        use image::codecs::jpeg::JpegEncoder;
-        JpegEncoder::new_with_quality(w, 100)
-    },
-    ".jpg"
-);
-
-def_encode_fn!(
-    encode_to_jpeg_90,
-    |w| {
-        use image::codecs::jpeg::JpegEncoder;
-        JpegEncoder::new_with_quality(w, 90)
-    },
-    ".jpg"
-);
+/// Whether both paths lead to the same existing file.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Replaces `image_path` with `data`, changing its extension to `extension` if the format
+/// changes, and returns the new path. Other extensions of the same format (`.jpeg`, `.tif`) are
+/// kept.
+///
+/// The data is written to a temporary file next to the input and renamed over it, so the input
+/// is never left half-written. `finish` is called on the temporary file before the rename. With a
+/// different extension, the input is removed once the new file is in place.
+fn replace_in_place(
+    image_path: &Path,
+    extension: &str,
+    data: &[u8],
+    backup_suffix: Option<&str>,
+    finish: impl FnOnce(&File) -> io::Result<()>,
+    force_overwrite: bool,
+) -> Result<PathBuf, Error> {
+    let same_extension = ImageFormat::from_path(image_path)
+        .is_ok_and(|format| ImageFormat::from_extension(extension) == Some(format));
+    let new_path = if same_extension {
+        image_path.to_owned()
+    } else {
+        image_path.with_extension(extension)
+    };
+    if !same_extension && !force_overwrite && new_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", new_path.display()),
+        )
+        .into());
+    }
 
-type EncodeFn = fn(&DynamicImage) -> Result<(Vec<u8>, &'static str), Error>;
+    if let Some(suffix) = backup_suffix {
+        let mut backup_name = image_path.as_os_str().to_owned();
+        backup_name.push(suffix);
+        let backup_path = PathBuf::from(backup_name);
+        if force_overwrite {
+            let _ = std::fs::remove_file(&backup_path);
+        }
+        // A hard link keeps the original in place until the rename below; copy where links are
+        // not supported
+        std::fs::hard_link(image_path, &backup_path).or_else(|e| {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+            std::fs::copy(image_path, &backup_path).map(drop)
+        })?;
+    }
 
-const ENCODE_STRATEGIES: &[EncodeFn] = &[encode_to_png, encode_to_jpeg_100, encode_to_jpeg_90];
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(new_path.file_name().expect("image_path must be a file"));
+    temp_name.push(".reenc-tmp");
+    let temp_path = new_path.with_file_name(temp_name);
+    let written = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            finish(&file)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temp_path, &new_path));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+
+    if !same_extension {
+        std::fs::remove_file(image_path)?;
+    }
+    Ok(new_path)
+}
+
+/// Tries each strategy in order and returns the first result that fits in the target size.
+fn encode_with_strategies<'a>(
+    image: &DynamicImage,
+    metadata: &Metadata,
+    strategies: &'a [Box<dyn Strategy>],
+    target_size: usize,
+) -> Result<Option<(&'a dyn Strategy, Encoded)>, Error> {
+    // Made the first time a strategy needs it
+    let eight_bit = std::cell::OnceCell::new();
+    for strategy in strategies {
+        let image = if strategy.wants_8bit() {
+            eight_bit
+                .get_or_init(|| tonemap::to_8bit(image))
+                .as_ref()
+                .unwrap_or(image)
+        } else {
+            image
+        };
+        if let Some(encoded) = strategy.encode(image, metadata, target_size)? {
+            return Ok(Some((strategy.as_ref(), encoded)));
+        }
+    }
+    Ok(None)
+}
+
+/// Shrinks `image` to fit within the given maximum dimensions, keeping the aspect ratio.
+///
+/// Returns `None` if the image already fits.
+fn fit_within(
+    image: &DynamicImage,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+) -> Option<DynamicImage> {
+    let (width, height) = image.dimensions();
+    let max_width = max_width.unwrap_or(width).max(1);
+    let max_height = max_height.unwrap_or(height).max(1);
+    if width <= max_width && height <= max_height {
+        return None;
+    }
+    Some(image.resize(max_width, max_height, image::imageops::FilterType::Lanczos3))
+}
+
+/// Lengths of the shorter side the downscale fallback steps through (2160p, 1440p, 1080p, ...).
+const DOWNSCALE_STEPS: &[u32] = &[2160, 1440, 1080, 720, 480];
+
+/// Resizes `image` so that its shorter side matches the next entry in [`DOWNSCALE_STEPS`].
+///
+/// Returns `None` if the image is already at or below the smallest step.
+fn downscale_step(image: &DynamicImage) -> Option<DynamicImage> {
+    let (width, height) = image.dimensions();
+    let short_side = width.min(height);
+    let step = *DOWNSCALE_STEPS.iter().find(|&&step| step < short_side)?;
+    let scale =
+        |len: u32| ((len as u64 * step as u64 + short_side as u64 / 2) / short_side as u64) as u32;
+    Some(image.resize_exact(
+        scale(width).max(1),
+        scale(height).max(1),
+        image::imageops::FilterType::Lanczos3,
+    ))
+}
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::fs::File;
     use std::sync::atomic::{AtomicUsize, Ordering};
 
     // Helper that automatically removes its temporary directory on drop
-    struct TempDir(PathBuf);
+    pub(crate) struct TempDir(PathBuf);
 
     impl TempDir {
-        fn new() -> Self {
+        pub(crate) fn new() -> Self {
             static COUNTER: AtomicUsize = AtomicUsize::new(0);
             let id = COUNTER.fetch_add(1, Ordering::Relaxed);
             let path = std::env::temp_dir().join(format!(
@@ -195,7 +1132,7 @@ mod tests {
             Self(path)
         }
 
-        fn path(&self) -> &Path {
+        pub(crate) fn path(&self) -> &Path {
             &self.0
         }
     }
@@ -206,6 +1143,34 @@ mod tests {
         }
     }
 
+    fn options(target_size: usize) -> EncodeOptions {
+        EncodeOptions {
+            target_size: Size::Bytes(target_size as u64),
+            margin: None,
+            max_width: None,
+            max_height: None,
+            downscale: false,
+            formats: None,
+            output_dir: None,
+            name_template: NameTemplate::default(),
+            strip_gps: false,
+            orientation: OrientationMode::Apply,
+            tone_map: tonemap::ToneMap::default(),
+            in_place: false,
+            backup_suffix: None,
+            preserve: Preserve::default(),
+            strategies: vec![
+                Box::new(strategy::PngStrategy::default()),
+                Box::new(strategy::WebpLosslessStrategy),
+                Box::new(strategy::JpegStrategy {
+                    qualities: 70..=100,
+                    subsampling: None,
+                    progressive: false,
+                }),
+            ],
+        }
+    }
+
     /// Saves a 300x300 solid RGB image as BMP (~270KB).
     /// Large enough to exceed target_size=200_000 and trigger encode.
     fn create_large_bmp(dir: &Path) -> PathBuf {
@@ -230,13 +1195,118 @@ mod tests {
         let path = create_small_png(dir.path());
         let orig_size = path.metadata().unwrap().len();
 
-        let result = re_encode_image(&path, usize::MAX, false).unwrap();
+        let result = re_encode_image(&path, &options(usize::MAX), false).unwrap();
 
         assert!(
             matches!(result, EncodeOutcome::Skipped { original_size } if original_size == orig_size)
         );
     }
 
+    // A small input is still converted if its format is not one of the allowed ones.
+    #[test]
+    fn test_convert_small_input_in_other_format() {
+        let dir = TempDir::new();
+        let path = create_large_bmp(dir.path());
+        let mut options = options(usize::MAX);
+        options.formats = Some(vec![ImageFormat::Png, ImageFormat::Jpeg]);
+
+        let result = re_encode_image(&path, &options, false).unwrap();
+
+        assert!(
+            matches!(
+                result,
+                EncodeOutcome::Encoded {
+                    strategy: "png",
+                    ..
+                }
+            ),
+            "expected a PNG, got {result:?}"
+        );
+
+        options.formats = Some(vec![ImageFormat::Bmp]);
+        let result = re_encode_image(&path, &options, true).unwrap();
+
+        assert!(matches!(result, EncodeOutcome::Skipped { .. }));
+    }
+
+    // With an output directory, the input's place below the base directory is kept.
+    #[test]
+    fn test_output_dir_mirrors_input_tree() {
+        let dir = TempDir::new();
+        let input_dir = dir.path().join("in").join("sub");
+        std::fs::create_dir_all(&input_dir).unwrap();
+        let path = create_large_bmp(&input_dir);
+        let mut options = options(200_000);
+        options.output_dir = Some(OutputDir {
+            dir: dir.path().join("out"),
+            base: dir.path().join("in"),
+        });
+
+        let result = re_encode_image(&path, &options, false).unwrap();
+
+        match result {
+            EncodeOutcome::Encoded { new_path, .. } => {
+                assert_eq!(
+                    new_path.parent().unwrap(),
+                    dir.path().join("out").join("sub")
+                );
+                assert!(new_path.exists());
+            }
+            EncodeOutcome::Skipped { .. } => panic!("expected Encoded, got Skipped"),
+        }
+    }
+
+    // An option's requirements are only reported missing when nothing gives them.
+    #[test]
+    fn test_missing_args() {
+        let args: Vec<OsString> = ["reenc-image", "x.bmp"].map(Into::into).into();
+        let with_palette: Vec<OsString> =
+            ["reenc-image", "-p", "16", "x.bmp"].map(Into::into).into();
+        let dither = [OsString::from("--dither")];
+
+        assert!(missing_args(&args, &[]).is_empty());
+        assert_eq!(missing_args(&args, &dither).len(), 1);
+        assert!(missing_args(&with_palette, &dither).is_empty());
+    }
+
+    // The common ancestor is taken over the inputs' directories.
+    #[test]
+    fn test_common_ancestor() {
+        let root = std::path::absolute("/data").unwrap();
+        let paths = [
+            root.join("shots/2024/a.png"),
+            root.join("shots/2025/b.png"),
+            root.join("shots/c.png"),
+        ];
+
+        assert_eq!(common_ancestor(&paths), root.join("shots"));
+        assert_eq!(common_ancestor(&paths[..1]), root.join("shots/2024"));
+    }
+
+    // Inputs reached through `..` still land inside the output directory.
+    #[test]
+    fn test_output_dir_with_parent_components() {
+        let root = std::path::absolute("/data").unwrap();
+        let paths = [
+            root.join("work/a/x.bmp"),
+            root.join("work/./a/../../other/y.bmp"),
+        ];
+        let output_dir = OutputDir {
+            dir: root.join("work/out"),
+            base: common_ancestor(&paths),
+        };
+
+        assert_eq!(output_dir.base, root);
+        assert_eq!(
+            output_dir.dir_for(&paths[0]).unwrap(),
+            root.join("work/out/work/a")
+        );
+        assert_eq!(
+            output_dir.dir_for(&paths[1]).unwrap(),
+            root.join("work/out/other")
+        );
+    }
+
     // On successful encoding, the output filename gets a -reenc suffix
     // and its size is below target_size.
     #[test]
@@ -245,7 +1315,7 @@ mod tests {
         let path = create_large_bmp(dir.path());
         let target = 200_000usize;
 
-        let result = re_encode_image(&path, target, false).unwrap();
+        let result = re_encode_image(&path, &options(target), false).unwrap();
 
         match result {
             EncodeOutcome::Encoded {
@@ -272,11 +1342,234 @@ mod tests {
         let dir = TempDir::new();
         let path = create_large_bmp(dir.path());
 
-        let result = re_encode_image(&path, 1, false);
+        let result = re_encode_image(&path, &options(1), false);
 
         assert!(matches!(result, Err(Error::ImageSizeExceedsTarget)));
     }
 
+    // Writing to a stream gives the encoded image, or the input as it is if it is skipped.
+    #[test]
+    fn test_re_encode_to_writer() {
+        let dir = TempDir::new();
+        let large = create_large_bmp(dir.path());
+        let small = create_small_png(dir.path());
+        let options = options(200_000);
+
+        let mut output = Vec::new();
+        let result = re_encode_to_writer(&large, &options, &mut output).unwrap();
+
+        assert!(
+            matches!(result, EncodeOutcome::Encoded { new_size, .. } if new_size == output.len() as u64)
+        );
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().dimensions(),
+            (300, 300)
+        );
+
+        let mut output = Vec::new();
+        let result = re_encode_to_writer(&small, &options, &mut output).unwrap();
+
+        assert!(matches!(result, EncodeOutcome::Skipped { .. }));
+        assert_eq!(output, std::fs::read(&small).unwrap());
+    }
+
+    // The EXIF orientation is applied to the pixels by default, or kept as a tag.
+    #[test]
+    fn test_orientation_modes() {
+        use image::ImageEncoder;
+
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8u32.to_le_bytes());
+        exif.extend(1u16.to_le_bytes());
+        exif.extend([0x12, 0x01, 3, 0]);
+        exif.extend(1u32.to_le_bytes());
+        exif.extend([6, 0, 0, 0]);
+        exif.extend(0u32.to_le_bytes());
+        let pixels = image::RgbImage::from_fn(40, 20, |x, _| image::Rgb([x as u8 * 6, 0, 0]));
+        let mut data = Vec::new();
+        let mut encoder = image::codecs::png::PngEncoder::new(&mut data);
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder
+            .write_image(&pixels, 40, 20, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let mut options = options(usize::MAX);
+        options.formats = Some(vec![ImageFormat::Jpeg]);
+        let orientation = |data: &[u8]| {
+            ImageReader::new(Cursor::new(data))
+                .with_guessed_format()
+                .unwrap()
+                .into_decoder()
+                .unwrap()
+                .orientation()
+                .unwrap()
+        };
+
+        let applied = re_encode_data(&data, &options).unwrap().unwrap();
+
+        assert_eq!(applied.dimensions, (20, 40));
+        assert_eq!(orientation(&applied.data), Orientation::NoTransforms);
+
+        options.orientation = OrientationMode::Tag;
+        let tagged = re_encode_data(&data, &options).unwrap().unwrap();
+
+        assert_eq!(tagged.dimensions, (40, 20));
+        assert_eq!(orientation(&tagged.data), Orientation::Rotate90);
+    }
+
+    // Radiance HDR inputs are converted even when small, with the highlights tone mapped rather
+    // than clipped to white.
+    #[test]
+    fn test_hdr_input_is_tone_mapped() {
+        let pixels: Vec<_> = (0..64)
+            .map(|i| image::Rgb([i as f32 / 16.0, 0.5, 0.0]))
+            .collect();
+        let mut data = Vec::new();
+        image::codecs::hdr::HdrEncoder::new(&mut data)
+            .encode(&pixels, 64, 1)
+            .unwrap();
+        let options = options(usize::MAX);
+
+        let reencoded = re_encode_data(&data, &options).unwrap().unwrap();
+
+        let decoded = image::load_from_memory(&reencoded.data).unwrap().to_rgb8();
+        let red: Vec<u8> = decoded.pixels().map(|p| p[0]).collect();
+        assert!(red.is_sorted(), "{red:?}");
+        assert!(
+            red[16] < red[32] && red[32] < 255,
+            "1.0 and 2.0 clip: {red:?}"
+        );
+    }
+
+    // In-place replacement in the same format keeps the input's name, whichever of the format's
+    // extensions it uses.
+    #[test]
+    fn test_in_place_keeps_extension_of_same_format() {
+        let dir = TempDir::new();
+        let path = dir.path().join("photo.jpeg");
+        std::fs::rename(create_large_bmp(dir.path()), &path).unwrap();
+        let mut options = options(200_000);
+        options.in_place = true;
+        options.strategies = vec![Box::new(strategy::JpegStrategy {
+            qualities: 70..=100,
+            subsampling: None,
+            progressive: false,
+        })];
+
+        let result = re_encode_image(&path, &options, false).unwrap();
+
+        assert!(
+            matches!(&result, EncodeOutcome::Encoded { new_path, .. } if *new_path == path),
+            "{result:?}"
+        );
+        assert!(!dir.path().join("photo.jpg").exists());
+        assert_eq!(
+            ImageFormat::from_path(&path).ok(),
+            image::guess_format(&std::fs::read(&path).unwrap()).ok()
+        );
+    }
+
+    // In-place replacement with a format change swaps the input for a file with the new
+    // extension, keeping a backup of the original.
+    #[test]
+    fn test_in_place_with_backup() {
+        let dir = TempDir::new();
+        let path = create_large_bmp(dir.path());
+        let original = std::fs::read(&path).unwrap();
+        let mut options = options(200_000);
+        options.in_place = true;
+        options.backup_suffix = Some(".bak".to_owned());
+
+        let result = re_encode_image(&path, &options, false).unwrap();
+
+        match result {
+            EncodeOutcome::Encoded { new_path, .. } => {
+                assert_eq!(new_path, dir.path().join("test.png"));
+                assert!(new_path.exists());
+                assert!(!path.exists(), "the input should be gone");
+                assert_eq!(
+                    std::fs::read(dir.path().join("test.bmp.bak")).unwrap(),
+                    original
+                );
+            }
+            EncodeOutcome::Skipped { .. } => panic!("expected Encoded, got Skipped"),
+        }
+        let leftovers = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(leftovers, 2, "no temporary file should be left");
+    }
+
+    // The outputs can get the input's timestamps and permissions, including in place.
+    #[test]
+    fn test_preserve_times_and_mode() {
+        let dir = TempDir::new();
+        let path = create_large_bmp(dir.path());
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        let input = File::options().write(true).open(&path).unwrap();
+        input
+            .set_times(std::fs::FileTimes::new().set_modified(modified))
+            .unwrap();
+        let mut permissions = input.metadata().unwrap().permissions();
+        permissions.set_readonly(true);
+        input.set_permissions(permissions).unwrap();
+        drop(input);
+        let mut options = options(200_000);
+        options.preserve = Preserve {
+            times: true,
+            mode: true,
+        };
+
+        for in_place in [false, true] {
+            options.in_place = in_place;
+            let result = re_encode_image(&path, &options, false).unwrap();
+
+            let EncodeOutcome::Encoded { new_path, .. } = result else {
+                panic!("expected Encoded, got {result:?}");
+            };
+            let metadata = std::fs::metadata(&new_path).unwrap();
+            assert_eq!(metadata.modified().unwrap(), modified);
+            assert!(metadata.permissions().readonly());
+            // Writable again, so that the next run and the cleanup can replace it
+            let mut permissions = metadata.permissions();
+            #[allow(clippy::permissions_set_readonly_false)]
+            permissions.set_readonly(false);
+            std::fs::set_permissions(&new_path, permissions).unwrap();
+        }
+    }
+
+    // In-place replacement does not clobber another file that has the new name.
+    #[test]
+    fn test_in_place_keeps_existing_file_with_new_extension() {
+        let dir = TempDir::new();
+        let path = create_large_bmp(dir.path());
+        std::fs::write(dir.path().join("test.png"), b"other").unwrap();
+        let mut options = options(200_000);
+        options.in_place = true;
+
+        let result = re_encode_image(&path, &options, false);
+
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert!(path.exists());
+        assert_eq!(
+            std::fs::read(dir.path().join("test.png")).unwrap(),
+            b"other"
+        );
+    }
+
+    // A template that maps the input onto itself is refused, even with force.
+    #[test]
+    fn test_error_output_is_input() {
+        let dir = TempDir::new();
+        let path = dir.path().join("test.png");
+        std::fs::rename(create_large_bmp(dir.path()), &path).unwrap();
+        let original = std::fs::read(&path).unwrap();
+        let mut options = options(200_000);
+        options.name_template = "{stem}.{ext}".parse().unwrap();
+
+        let result = re_encode_image(&path, &options, true);
+
+        assert!(matches!(result, Err(Error::OutputIsInput(_))), "{result:?}");
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
     // Returns an IO error if the output already exists and force=false.
     #[test]
     fn test_error_output_already_exists() {
@@ -285,7 +1578,7 @@ mod tests {
         // PNG is tried first, so pre-create test-reenc.png to trigger the error.
         File::create(dir.path().join("test-reenc.png")).unwrap();
 
-        let result = re_encode_image(&path, 200_000, false);
+        let result = re_encode_image(&path, &options(200_000), false);
 
         assert!(matches!(result, Err(Error::Io(_))));
     }
@@ -297,8 +1590,49 @@ mod tests {
         let path = create_large_bmp(dir.path());
         File::create(dir.path().join("test-reenc.png")).unwrap();
 
-        let result = re_encode_image(&path, 200_000, true).unwrap();
+        let result = re_encode_image(&path, &options(200_000), true).unwrap();
 
         assert!(matches!(result, EncodeOutcome::Encoded { .. }));
     }
+
+    // Downscaling steps the shorter side down to the next entry and keeps the aspect ratio.
+    #[test]
+    fn test_downscale_step_keeps_aspect_ratio() {
+        let img = DynamicImage::new_rgb8(1920, 1080);
+
+        let smaller = downscale_step(&img).unwrap();
+
+        assert_eq!(smaller.dimensions(), (1280, 720));
+    }
+
+    // Images at or below the smallest step cannot be downscaled any further.
+    #[test]
+    fn test_downscale_step_stops_at_smallest() {
+        let img = DynamicImage::new_rgb8(300, 300);
+
+        assert!(downscale_step(&img).is_none());
+    }
+
+    // A relative target is resolved against each input, and the margin is taken off the target.
+    #[test]
+    fn test_target_size_with_percent_and_margin() {
+        let options = EncodeOptions {
+            target_size: Size::Percent(50.0),
+            margin: Some(Size::Percent(10.0)),
+            ..options(0)
+        };
+
+        assert_eq!(options.target_size_for(2000), 900);
+    }
+
+    // Images are shrunk to fit the maximum dimensions, keeping the aspect ratio.
+    #[test]
+    fn test_fit_within_max_dimensions() {
+        let img = DynamicImage::new_rgb8(400, 100);
+
+        let smaller = fit_within(&img, Some(200), Some(200)).unwrap();
+
+        assert_eq!(smaller.dimensions(), (200, 50));
+        assert!(fit_within(&img, None, Some(100)).is_none());
+    }
 }
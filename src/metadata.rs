@@ -0,0 +1,256 @@
+//! Metadata carried over from the input to the re-encoded output.
+
+use image::{ImageDecoder, ImageEncoder, metadata::Orientation};
+
+/// Tag of the pointer from IFD0 to the GPS IFD.
+const GPS_IFD_POINTER: u16 = 0x8825;
+
+/// What to do with the EXIF orientation of the input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OrientationMode {
+    /// Rotate and flip the pixels as the tag says, and reset the tag
+    #[default]
+    Apply,
+    /// Keep the pixels as they are stored and write the tag back
+    Tag,
+}
+
+/// Metadata of the input image.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// EXIF block in TIFF layout (starting with `II*\0` or `MM\0*`)
+    pub exif: Option<Vec<u8>>,
+    /// Embedded ICC color profile
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads the metadata from a decoder. Unreadable metadata is left out rather than failing the
+    /// whole image.
+    pub fn read(decoder: &mut impl ImageDecoder) -> Self {
+        let exif = decoder.exif_metadata().ok().flatten().map(|mut exif| {
+            // Some WebP writers keep the JPEG APP1 header in the EXIF chunk
+            if exif.starts_with(b"Exif\0\0") {
+                exif.drain(..6);
+            }
+            exif
+        });
+        let icc_profile = decoder.icc_profile().ok().flatten();
+        Self { exif, icc_profile }
+    }
+
+    /// Hands the metadata to an encoder. Encoders that cannot store some of it simply drop it.
+    pub fn apply(&self, encoder: &mut impl ImageEncoder) {
+        if let Some(exif) = &self.exif {
+            let _ = encoder.set_exif_metadata(exif.clone());
+        }
+        if let Some(icc_profile) = &self.icc_profile {
+            let _ = encoder.set_icc_profile(icc_profile.clone());
+        }
+    }
+
+    /// Resets the orientation tag, for pixels that have already been rotated.
+    pub fn clear_orientation(&mut self) {
+        if let Some(exif) = &mut self.exif {
+            let _ = Orientation::remove_from_exif_chunk(exif);
+        }
+    }
+
+    /// Removes the location data from the EXIF block, keeping the other tags. Returns whether
+    /// there was any.
+    pub fn strip_gps(&mut self) -> bool {
+        self.exif.as_deref_mut().is_some_and(strip_gps)
+    }
+}
+
+/// Removes the GPS IFD from an EXIF block in place.
+///
+/// The pointer entry is taken out of IFD0 and the GPS IFD and its values are zeroed. Everything
+/// else stays where it is, so the offsets used by the other tags remain valid.
+fn strip_gps(exif: &mut [u8]) -> bool {
+    let Some(tiff) = Tiff::new(exif) else {
+        return false;
+    };
+    let Some(ifd0) = tiff.ifd0() else {
+        return false;
+    };
+    let Some(index) = tiff.find_entry(ifd0, GPS_IFD_POINTER) else {
+        return false;
+    };
+    let entry = ifd0 + 2 + 12 * index;
+    let gps_ifd = tiff.u32(entry + 8);
+    let count = tiff.u16(ifd0).unwrap_or(0) as usize;
+    let ifd_end = ifd0 + 2 + 12 * count + 4;
+    if ifd_end > exif.len() {
+        return false;
+    }
+
+    // Zero the GPS IFD, including the values stored outside of it
+    let mut wipe = Vec::new();
+    if let Some(gps_ifd) = gps_ifd.map(|offset| offset as usize)
+        && let Some(gps_count) = tiff.u16(gps_ifd)
+    {
+        let gps_count = gps_count as usize;
+        for i in 0..gps_count {
+            let gps_entry = gps_ifd + 2 + 12 * i;
+            if let Some(len) = tiff.value_len(gps_entry)
+                && len > 4
+                && let Some(offset) = tiff.u32(gps_entry + 8)
+            {
+                wipe.push(offset as usize..(offset as usize).saturating_add(len));
+            }
+        }
+        wipe.push(gps_ifd..gps_ifd + 2 + 12 * gps_count + 4);
+    }
+    let big_endian = tiff.big_endian;
+
+    for range in wipe {
+        let end = range.end.min(exif.len());
+        if range.start < end {
+            exif[range.start..end].fill(0);
+        }
+    }
+
+    // Take the entry out of IFD0, moving the later entries and the next IFD offset up
+    exif.copy_within(entry + 12..ifd_end, entry);
+    exif[ifd_end - 12..ifd_end].fill(0);
+    let new_count = (count as u16 - 1).to_le_bytes();
+    let new_count = if big_endian {
+        [new_count[1], new_count[0]]
+    } else {
+        new_count
+    };
+    exif[ifd0..ifd0 + 2].copy_from_slice(&new_count);
+    true
+}
+
+/// Read access to the TIFF structure of an EXIF block. Out-of-range reads give `None`.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn ifd0(&self) -> Option<usize> {
+        self.u32(4).map(|offset| offset as usize)
+    }
+
+    /// Index of the entry with `tag` in the IFD at `ifd`.
+    fn find_entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = self.u16(ifd)? as usize;
+        (0..count).find(|i| self.u16(ifd + 2 + 12 * i) == Some(tag))
+    }
+
+    /// Size in bytes of the value of the entry at `entry`.
+    fn value_len(&self, entry: usize) -> Option<usize> {
+        let unit = match self.u16(entry + 2)? {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        };
+        (self.u32(entry + 4)? as usize).checked_mul(unit)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A little-endian EXIF block with a Make tag and a GPS IFD holding a latitude.
+    pub(crate) fn exif_with_gps() -> Vec<u8> {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8u32.to_le_bytes());
+        // IFD0 at 8: Make ("abc"), GPS pointer, next IFD = 0
+        exif.extend(2u16.to_le_bytes());
+        exif.extend([0x0F, 0x01, 2, 0]);
+        exif.extend(4u32.to_le_bytes());
+        exif.extend(*b"abc\0");
+        exif.extend(GPS_IFD_POINTER.to_le_bytes());
+        exif.extend(4u16.to_le_bytes());
+        exif.extend(1u32.to_le_bytes());
+        exif.extend(38u32.to_le_bytes());
+        exif.extend(0u32.to_le_bytes());
+        // GPS IFD at 38: GPSLatitude, three rationals at 56
+        exif.extend(1u16.to_le_bytes());
+        exif.extend([2, 0, 5, 0]);
+        exif.extend(3u32.to_le_bytes());
+        exif.extend(56u32.to_le_bytes());
+        exif.extend(0u32.to_le_bytes());
+        for value in [35u32, 1, 41, 1, 22, 1] {
+            exif.extend(value.to_le_bytes());
+        }
+        exif
+    }
+
+    // Clearing the orientation resets the tag to "no transforms" instead of removing it.
+    #[test]
+    fn test_clear_orientation() {
+        let mut exif = b"MM\0*".to_vec();
+        exif.extend(8u32.to_be_bytes());
+        exif.extend(1u16.to_be_bytes());
+        exif.extend([0x01, 0x12, 0, 3]);
+        exif.extend(1u32.to_be_bytes());
+        exif.extend([0, 6, 0, 0]);
+        exif.extend(0u32.to_be_bytes());
+        let mut metadata = Metadata {
+            exif: Some(exif),
+            ..Metadata::default()
+        };
+        let orientation =
+            |metadata: &Metadata| Orientation::from_exif_chunk(metadata.exif.as_deref().unwrap());
+        assert_eq!(orientation(&metadata), Some(Orientation::Rotate90));
+
+        metadata.clear_orientation();
+
+        assert_eq!(orientation(&metadata), Some(Orientation::NoTransforms));
+        Metadata::default().clear_orientation();
+    }
+
+    // Stripping GPS drops the pointer and wipes the coordinates, but keeps the other tags.
+    #[test]
+    fn test_strip_gps() {
+        let mut metadata = Metadata {
+            exif: Some(exif_with_gps()),
+            ..Metadata::default()
+        };
+
+        assert!(metadata.strip_gps());
+
+        let exif = metadata.exif.as_deref().unwrap();
+        let tiff = Tiff::new(exif).unwrap();
+        assert_eq!(tiff.find_entry(8, GPS_IFD_POINTER), None);
+        assert_eq!(tiff.find_entry(8, 0x010F), Some(0));
+        assert_eq!(tiff.u32(8 + 2 + 12), Some(0), "next IFD offset moved up");
+        assert!(exif[38..].iter().all(|&b| b == 0));
+        assert!(!metadata.clone().strip_gps());
+    }
+}
@@ -0,0 +1,219 @@
+//! Palette quantization (NeuQuant) and indexed PNG output.
+
+use crate::metadata::Metadata;
+use color_quant::NeuQuant;
+use image::{DynamicImage, RgbaImage};
+use std::collections::HashMap;
+
+/// NeuQuant sampling factor: 1 is the slowest and most accurate, 30 the fastest.
+const SAMPLE_FACTOR: i32 = 10;
+
+/// Quantizes `image` down to at most `colors` colors and encodes it as an indexed PNG, with the
+/// EXIF block and ICC profile of `metadata`. An image that already has few enough colors keeps
+/// them exactly; NeuQuant would shift them slightly.
+///
+/// With `dither`, the quantization error is spread to neighbouring pixels (Floyd-Steinberg),
+/// which hides banding in gradients at the cost of a slightly larger file.
+pub fn encode_palette_png(
+    image: &DynamicImage,
+    colors: u16,
+    dither: bool,
+    metadata: &Metadata,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let rgba = image.to_rgba8();
+    let (palette, indices) = match exact_palette(&rgba, colors) {
+        Some(index_of) => {
+            let mut palette = vec![[0; 4]; index_of.len()];
+            for (&color, &index) in &index_of {
+                palette[index as usize] = color;
+            }
+            let indices = rgba.pixels().map(|p| index_of[&p.0]).collect();
+            (palette, indices)
+        }
+        None => {
+            let quantizer = NeuQuant::new(SAMPLE_FACTOR, colors as usize, rgba.as_raw());
+            let mut indices = if dither {
+                map_dithered(&rgba, &quantizer)
+            } else {
+                rgba.pixels()
+                    .map(|p| quantizer.index_of(&p.0) as u8)
+                    .collect()
+            };
+            let palette: Vec<_> = quantizer
+                .color_map_rgba()
+                .chunks_exact(4)
+                .map(|c| [c[0], c[1], c[2], c[3]])
+                .collect();
+            (remove_unused(&palette, &mut indices), indices)
+        }
+    };
+    let (rgb, alpha): (Vec<_>, Vec<_>) = palette.iter().map(|&[r, g, b, a]| ([r, g, b], a)).unzip();
+
+    let depth = match palette.len() {
+        0..=2 => png::BitDepth::One,
+        3..=4 => png::BitDepth::Two,
+        5..=16 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    };
+
+    let mut info = png::Info::with_size(rgba.width(), rgba.height());
+    info.color_type = png::ColorType::Indexed;
+    info.bit_depth = depth;
+    info.palette = Some(rgb.concat().into());
+    if let Some(last_translucent) = alpha.iter().rposition(|&a| a != 255) {
+        info.trns = Some(alpha[..=last_translucent].to_vec().into());
+    }
+    info.exif_metadata = metadata.exif.as_deref().map(Into::into);
+    info.icc_profile = metadata.icc_profile.as_deref().map(Into::into);
+
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::with_info(&mut buf, info)?;
+    encoder.set_compression(png::Compression::High);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pack_rows(&indices, rgba.width() as usize, depth as usize))?;
+    writer.finish()?;
+    Ok(buf)
+}
+
+/// The image's own colors with their palette indices, if there are no more than `colors` of them.
+fn exact_palette(image: &RgbaImage, colors: u16) -> Option<HashMap<[u8; 4], u8>> {
+    let mut index_of = HashMap::new();
+    for pixel in image.pixels() {
+        if !index_of.contains_key(&pixel.0) {
+            if index_of.len() >= colors as usize {
+                return None;
+            }
+            index_of.insert(pixel.0, index_of.len() as u8);
+        }
+    }
+    Some(index_of)
+}
+
+/// Drops the palette entries no pixel uses, renumbering `indices` to match, so that the PNG gets
+/// the smallest palette and bit depth possible.
+fn remove_unused(palette: &[[u8; 4]], indices: &mut [u8]) -> Vec<[u8; 4]> {
+    let mut renumbered = [None; 256];
+    let mut used = Vec::new();
+    for index in indices {
+        let old = *index as usize;
+        *index = *renumbered[old].get_or_insert_with(|| {
+            used.push(palette[old]);
+            (used.len() - 1) as u8
+        });
+    }
+    used
+}
+
+/// Maps every pixel to its palette index, diffusing the error with Floyd-Steinberg weights.
+fn map_dithered(image: &RgbaImage, quantizer: &NeuQuant) -> Vec<u8> {
+    let width = image.width() as usize;
+    let mut indices = Vec::with_capacity(image.as_raw().len() / 4);
+    // Accumulated error for the current and the next row, with one pixel of padding on each side
+    let mut current = vec![[0f32; 4]; width + 2];
+    let mut next = vec![[0f32; 4]; width + 2];
+
+    for row in image.rows() {
+        for (x, pixel) in row.enumerate() {
+            let mut wanted = [0u8; 4];
+            let mut exact = [0f32; 4];
+            for c in 0..4 {
+                exact[c] = pixel.0[c] as f32 + current[x + 1][c];
+                wanted[c] = exact[c].round().clamp(0.0, 255.0) as u8;
+            }
+            let index = quantizer.index_of(&wanted);
+            let chosen = quantizer
+                .lookup(index)
+                .expect("index comes from the quantizer");
+            indices.push(index as u8);
+
+            for c in 0..4 {
+                let error = exact[c] - chosen[c] as f32;
+                current[x + 2][c] += error * 7.0 / 16.0;
+                next[x][c] += error * 3.0 / 16.0;
+                next[x + 1][c] += error * 5.0 / 16.0;
+                next[x + 2][c] += error * 1.0 / 16.0;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.fill([0.0; 4]);
+    }
+    indices
+}
+
+/// Packs one index per pixel into PNG scanlines of the given bit depth.
+fn pack_rows(indices: &[u8], width: usize, depth: usize) -> Vec<u8> {
+    if depth == 8 {
+        return indices.to_vec();
+    }
+    let per_byte = 8 / depth;
+    let mut packed = Vec::with_capacity(indices.len().div_ceil(per_byte));
+    for row in indices.chunks_exact(width) {
+        for chunk in row.chunks(per_byte) {
+            let byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |acc, (i, &index)| acc | index << (8 - depth * (i + 1)));
+            packed.push(byte);
+        }
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashSet, io::Cursor};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }))
+    }
+
+    fn distinct_colors(data: &[u8]) -> usize {
+        let decoded = image::load_from_memory(data).unwrap().to_rgba8();
+        decoded.pixels().map(|p| p.0).collect::<HashSet<_>>().len()
+    }
+
+    // The output never has more colors than requested.
+    #[test]
+    fn test_palette_limits_colors() {
+        let data = encode_palette_png(&gradient(), 16, false, &Metadata::default()).unwrap();
+
+        assert!(distinct_colors(&data) <= 16);
+    }
+
+    // An image with few enough colors comes back exactly, with a palette of just those colors.
+    #[test]
+    fn test_palette_keeps_exact_colors() {
+        let colors = [
+            [255, 255, 255, 255],
+            [0, 0, 0, 255],
+            [230, 40, 40, 255],
+            [40, 200, 90, 255],
+            [30, 60, 220, 255],
+            [250, 220, 0, 128],
+        ];
+        let image = RgbaImage::from_fn(30, 20, |x, y| {
+            image::Rgba(colors[((x / 5 + y) % 6) as usize])
+        });
+
+        let data =
+            encode_palette_png(&image.clone().into(), 256, true, &Metadata::default()).unwrap();
+
+        assert_eq!(image::load_from_memory(&data).unwrap().to_rgba8(), image);
+        let reader = png::Decoder::new(Cursor::new(&data)).read_info().unwrap();
+        assert_eq!(reader.info().palette.as_ref().unwrap().len(), 6 * 3);
+        assert_eq!(reader.info().bit_depth, png::BitDepth::Four);
+    }
+
+    // Dithering still stays within the palette, and packed low bit depths decode correctly.
+    #[test]
+    fn test_palette_dithered_low_depth() {
+        let data = encode_palette_png(&gradient(), 4, true, &Metadata::default()).unwrap();
+
+        let decoded = image::load_from_memory(&data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 64));
+        assert!(distinct_colors(&data) <= 4);
+    }
+}
@@ -0,0 +1,111 @@
+//! Built-in presets for the sites we post to.
+//!
+//! Limits change from time to time; the values here are the documented ones at the time of
+//! writing, rounded down where the site is vague about units.
+
+use crate::size::Size;
+
+/// Target size, dimension and format limits of one upload target.
+#[derive(Debug, Clone, Copy)]
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub target_size: Size,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Strategy chain in `--strategy` syntax, limited to the formats the site accepts
+    pub strategies: &'static str,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "mixi2",
+        description: "mixi2: 15 MiB",
+        target_size: Size::Bytes(15 << 20),
+        max_width: None,
+        max_height: None,
+        strategies: "png,jpeg",
+    },
+    Preset {
+        name: "discord",
+        description: "Discord (without Nitro): 10 MB",
+        target_size: Size::Bytes(10_000_000),
+        max_width: None,
+        max_height: None,
+        strategies: "png,webp:lossless,jpeg",
+    },
+    Preset {
+        name: "bluesky",
+        description: "Bluesky: 1 MB, 2000x2000",
+        target_size: Size::Bytes(1_000_000),
+        max_width: Some(2000),
+        max_height: Some(2000),
+        strategies: "png,webp:lossless,jpeg",
+    },
+    Preset {
+        name: "mastodon",
+        description: "Mastodon (default server limits): 16 MiB, 3840 px on the long side",
+        target_size: Size::Bytes(16 << 20),
+        max_width: Some(3840),
+        max_height: Some(3840),
+        strategies: "png,webp:lossless,jpeg",
+    },
+    Preset {
+        name: "x",
+        description: "X (Twitter): 5 MB, 4096x4096",
+        target_size: Size::Bytes(5_000_000),
+        max_width: Some(4096),
+        max_height: Some(4096),
+        strategies: "png,jpeg",
+    },
+];
+
+/// Options (by long name) that a preset sets. Given explicitly, they take precedence over it.
+pub const OPTIONS: &[&str] = &["target-size", "max-width", "max-height", "strategy"];
+
+/// Looks up a preset by name.
+pub fn find(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|p| p.name == name)
+}
+
+/// Preset names and descriptions, for `--preset`'s possible values.
+pub fn possible_values() -> impl Iterator<Item = clap::builder::PossibleValue> {
+    PRESETS
+        .iter()
+        .map(|p| clap::builder::PossibleValue::new(p.name).help(p.description))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec;
+
+    // Every preset has a unique name and a strategy chain that parses.
+    #[test]
+    fn test_presets_are_valid() {
+        let defaults = spec::Defaults {
+            min_quality: 70,
+            png_effort: 0,
+            palette_colors: None,
+            dither: false,
+            jpeg_subsampling: None,
+            progressive: false,
+            #[cfg(feature = "avif")]
+            avif_speed: 6,
+            #[cfg(feature = "avif")]
+            avif_max_quality: 90,
+        };
+        for (i, preset) in PRESETS.iter().enumerate() {
+            assert!(
+                PRESETS[..i].iter().all(|p| p.name != preset.name),
+                "duplicate preset {}",
+                preset.name
+            );
+            assert!(
+                spec::parse_chain(preset.strategies, &defaults).is_ok(),
+                "bad chain in preset {}",
+                preset.name
+            );
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Parsing and formatting of byte sizes, such as `15MiB`, `8MB`, `500k` or `50%`.
+
+use std::{fmt, str::FromStr};
+
+/// A size given either in bytes or relative to some other size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Bytes(u64),
+    /// Percentage of the reference size: each input's size for the target, the target for the
+    /// margin
+    Percent(f64),
+}
+
+impl Size {
+    /// Resolves the size against `reference` (used by [`Size::Percent`]).
+    pub fn resolve(self, reference: u64) -> u64 {
+        match self {
+            Size::Bytes(bytes) => bytes,
+            Size::Percent(percent) => (reference as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.trim().parse::<f64>() {
+                Ok(p) if p.is_finite() && p >= 0.0 => Ok(Size::Percent(p)),
+                _ => Err(format!("invalid percentage: {s}")),
+            };
+        }
+
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "m" | "mb" => 1000 * 1000,
+            "g" | "gb" => 1000 * 1000 * 1000,
+            "kib" => 1 << 10,
+            "mib" => 1 << 20,
+            "gib" => 1 << 30,
+            _ => {
+                return Err(format!(
+                    "unknown unit in {s:?} (expected B, k, KB, KiB, M, MB, MiB, G, GB, GiB or %)"
+                ));
+            }
+        };
+        if let Ok(bytes) = number.parse::<u64>() {
+            return bytes
+                .checked_mul(multiplier)
+                .map(Size::Bytes)
+                .ok_or_else(|| format!("size is too large: {s}"));
+        }
+        match number.parse::<f64>() {
+            Ok(n) if n.is_finite() && n >= 0.0 => Ok(Size::Bytes((n * multiplier as f64) as u64)),
+            _ => Err(format!("invalid size: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Size::Bytes(bytes) => write!(f, "{}", HumanBytes(*bytes)),
+            Size::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+/// Formats a byte count with binary units, e.g. `15.00 MiB`.
+#[derive(Debug, Clone, Copy)]
+pub struct HumanBytes(pub u64);
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} bytes", self.0);
+        }
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{value:.2} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plain numbers are bytes; decimal and binary units are told apart.
+    #[test]
+    fn test_parse_units() {
+        assert_eq!("15728640".parse(), Ok(Size::Bytes(15_728_640)));
+        assert_eq!("15MiB".parse(), Ok(Size::Bytes(15 << 20)));
+        assert_eq!("8MB".parse(), Ok(Size::Bytes(8_000_000)));
+        assert_eq!("500k".parse(), Ok(Size::Bytes(500_000)));
+        assert_eq!("1.5 KiB".parse(), Ok(Size::Bytes(1536)));
+        assert_eq!("50%".parse(), Ok(Size::Percent(50.0)));
+        assert!("15 parsecs".parse::<Size>().is_err());
+        assert!("-1%".parse::<Size>().is_err());
+    }
+
+    // Percentages resolve against the reference size, byte counts ignore it.
+    #[test]
+    fn test_resolve() {
+        assert_eq!(Size::Percent(50.0).resolve(3000), 1500);
+        assert_eq!(Size::Bytes(1234).resolve(3000), 1234);
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(HumanBytes(512).to_string(), "512 bytes");
+        assert_eq!(HumanBytes(15 << 20).to_string(), "15.00 MiB");
+        assert_eq!(HumanBytes(1536).to_string(), "1.50 KiB");
+    }
+}
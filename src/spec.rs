@@ -0,0 +1,489 @@
+//! Parser for strategy chain specifications, as given to `--strategy`.
+//!
+//! A chain is a comma-separated list of strategies, each of them a name optionally followed by
+//! colon-separated parameters: `png:best,webp:lossless,jpeg:95:420,jpeg:70-90:progressive`.
+//! Parameters are either `key=value` pairs or bare shorthands such as a quality or a subsampling
+//! mode. Anything left out is taken from [`Defaults`].
+
+use crate::{
+    jpeg::Subsampling,
+    strategy::{self, Strategy},
+};
+use std::{ops::RangeInclusive, str::FromStr};
+
+/// Parameter values used where a spec does not give them, filled from the other command line
+/// options.
+#[derive(Debug, Clone)]
+pub struct Defaults {
+    pub min_quality: u8,
+    pub png_effort: u8,
+    /// Colors for the palette strategy; the default chain only includes it if this is set
+    pub palette_colors: Option<u16>,
+    pub dither: bool,
+    pub jpeg_subsampling: Option<Subsampling>,
+    pub progressive: bool,
+    #[cfg(feature = "avif")]
+    pub avif_speed: u8,
+    #[cfg(feature = "avif")]
+    pub avif_max_quality: u8,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SpecError {
+    #[error("empty strategy in the chain")]
+    Empty,
+
+    #[error("unknown strategy \"{0}\" (expected one of: png, webp, palette, jpeg, avif)")]
+    UnknownStrategy(String),
+
+    #[cfg(not(feature = "avif"))]
+    #[error("strategy \"{0}\" is not available in this build (enable the \"{0}\" feature)")]
+    Unavailable(String),
+
+    #[error("unknown parameter \"{param}\" for strategy \"{strategy}\"")]
+    UnknownParam {
+        strategy: &'static str,
+        param: String,
+    },
+
+    #[error("invalid {param} \"{value}\" for strategy \"{strategy}\": {reason}")]
+    InvalidValue {
+        strategy: &'static str,
+        param: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl Defaults {
+    /// The AVIF quality range, from `-q` up to `--avif-quality`.
+    #[cfg(feature = "avif")]
+    fn avif_qualities(&self) -> Result<RangeInclusive<u8>, SpecError> {
+        let value = format!("{}-{}", self.min_quality, self.avif_max_quality);
+        quality_range("avif", &value, self.min_quality, self.avif_max_quality)
+    }
+}
+
+/// The chain used when `--strategy` is not given: lossless first, then the lossy fallbacks.
+pub fn default_chain(defaults: &Defaults) -> Result<Vec<Box<dyn Strategy>>, SpecError> {
+    let mut strategies: Vec<Box<dyn Strategy>> = vec![
+        Box::new(strategy::PngStrategy {
+            effort: defaults.png_effort,
+        }),
+        Box::new(strategy::WebpLosslessStrategy),
+    ];
+    if let Some(colors) = defaults.palette_colors {
+        strategies.push(Box::new(strategy::PaletteStrategy {
+            colors,
+            dither: defaults.dither,
+        }));
+    }
+    #[cfg(feature = "avif")]
+    strategies.push(Box::new(strategy::AvifStrategy {
+        qualities: defaults.avif_qualities()?,
+        speed: defaults.avif_speed,
+    }));
+    strategies.push(Box::new(strategy::JpegStrategy {
+        qualities: defaults.min_quality..=100,
+        subsampling: defaults.jpeg_subsampling,
+        progressive: defaults.progressive,
+    }));
+    Ok(strategies)
+}
+
+/// Adds the palette strategy of `-p` to a chain without one, after the lossless strategies as in
+/// the default chain.
+pub fn add_palette(chain: &mut Vec<Box<dyn Strategy>>, defaults: &Defaults) {
+    let Some(colors) = defaults.palette_colors else {
+        return;
+    };
+    if chain.iter().any(|s| s.name() == "palette") {
+        return;
+    }
+    let position = chain
+        .iter()
+        .position(|s| matches!(s.name(), "jpeg" | "avif"))
+        .unwrap_or(chain.len());
+    chain.insert(
+        position,
+        Box::new(strategy::PaletteStrategy {
+            colors,
+            dither: defaults.dither,
+        }),
+    );
+}
+
+/// Parses a comma-separated strategy chain.
+pub fn parse_chain(spec: &str, defaults: &Defaults) -> Result<Vec<Box<dyn Strategy>>, SpecError> {
+    spec.split(',')
+        .map(|item| parse_strategy(item, defaults))
+        .collect()
+}
+
+/// Parses one `name[:param...]` item of a chain.
+fn parse_strategy(item: &str, defaults: &Defaults) -> Result<Box<dyn Strategy>, SpecError> {
+    let mut parts = item.split(':').map(str::trim);
+    let name = parts.next().unwrap_or_default();
+    let params = parts.map(Param::new);
+
+    match name {
+        "" => Err(SpecError::Empty),
+        "png" => {
+            let mut s = strategy::PngStrategy {
+                effort: defaults.png_effort,
+            };
+            for param in params {
+                match param {
+                    Param::Flag("best") => s.effort = 0,
+                    Param::Flag("optimize") => s.effort = strategy::MAX_PNG_EFFORT,
+                    Param::Flag(value) | Param::Pair("effort", value) => {
+                        s.effort =
+                            parse_number("png", "effort", value, 0..=strategy::MAX_PNG_EFFORT)?;
+                    }
+                    param => return Err(param.unknown("png")),
+                }
+            }
+            Ok(Box::new(s))
+        }
+        "webp" => {
+            for param in params {
+                match param {
+                    Param::Flag("lossless") => {}
+                    Param::Flag(value @ "lossy") | Param::Pair("lossless", value) => {
+                        let lossless = value != "lossy" && parse_bool("webp", "lossless", value)?;
+                        if !lossless {
+                            return Err(invalid(
+                                "webp",
+                                "lossless",
+                                value,
+                                "lossy WebP is not supported",
+                            ));
+                        }
+                    }
+                    param => return Err(param.unknown("webp")),
+                }
+            }
+            Ok(Box::new(strategy::WebpLosslessStrategy))
+        }
+        "palette" => {
+            let mut s = strategy::PaletteStrategy {
+                colors: defaults.palette_colors.unwrap_or(256),
+                dither: defaults.dither,
+            };
+            for param in params {
+                match param {
+                    Param::Flag("dither") => s.dither = true,
+                    Param::Pair("dither", value) => {
+                        s.dither = parse_bool("palette", "dither", value)?
+                    }
+                    Param::Flag(value) | Param::Pair("colors", value) => {
+                        s.colors = parse_number("palette", "colors", value, 2..=256)?;
+                    }
+                    param => return Err(param.unknown("palette")),
+                }
+            }
+            Ok(Box::new(s))
+        }
+        "jpeg" | "jpg" => {
+            let mut s = strategy::JpegStrategy {
+                qualities: defaults.min_quality..=100,
+                subsampling: defaults.jpeg_subsampling,
+                progressive: defaults.progressive,
+            };
+            for param in params {
+                match param {
+                    Param::Flag("progressive") => s.progressive = true,
+                    Param::Flag("baseline") => s.progressive = false,
+                    Param::Pair("progressive", value) => {
+                        s.progressive = parse_bool("jpeg", "progressive", value)?;
+                    }
+                    Param::Flag(value @ ("444" | "422" | "420"))
+                    | Param::Pair("subsampling", value) => {
+                        s.subsampling = Some(parse_subsampling(value)?);
+                    }
+                    Param::Flag(value) | Param::Pair("quality", value) => {
+                        s.qualities = parse_qualities("jpeg", value)?;
+                    }
+                    param => return Err(param.unknown("jpeg")),
+                }
+            }
+            Ok(Box::new(s))
+        }
+        #[cfg(feature = "avif")]
+        "avif" => {
+            let mut qualities = None;
+            let mut speed = defaults.avif_speed;
+            for param in params {
+                match param {
+                    Param::Pair("speed", value) => {
+                        speed = parse_number("avif", "speed", value, 1..=10)?;
+                    }
+                    Param::Flag(value) | Param::Pair("quality", value) => {
+                        qualities = Some(parse_qualities("avif", value)?);
+                    }
+                    param => return Err(param.unknown("avif")),
+                }
+            }
+            // The range from the options is only checked if the spec does not replace it
+            let qualities = match qualities {
+                Some(qualities) => qualities,
+                None => defaults.avif_qualities()?,
+            };
+            Ok(Box::new(strategy::AvifStrategy { qualities, speed }))
+        }
+        #[cfg(not(feature = "avif"))]
+        "avif" => Err(SpecError::Unavailable(name.to_owned())),
+        _ => Err(SpecError::UnknownStrategy(name.to_owned())),
+    }
+}
+
+/// One parameter of a strategy: either `key=value` or a bare value.
+#[derive(Debug, Clone, Copy)]
+enum Param<'a> {
+    Pair(&'a str, &'a str),
+    Flag(&'a str),
+}
+
+impl<'a> Param<'a> {
+    fn new(param: &'a str) -> Self {
+        match param.split_once('=') {
+            Some((key, value)) => Param::Pair(key.trim(), value.trim()),
+            None => Param::Flag(param),
+        }
+    }
+
+    fn unknown(self, strategy: &'static str) -> SpecError {
+        let param = match self {
+            Param::Pair(key, _) => key,
+            Param::Flag(value) => value,
+        };
+        SpecError::UnknownParam {
+            strategy,
+            param: param.to_owned(),
+        }
+    }
+}
+
+fn invalid(
+    strategy: &'static str,
+    param: &'static str,
+    value: &str,
+    reason: impl Into<String>,
+) -> SpecError {
+    SpecError::InvalidValue {
+        strategy,
+        param,
+        value: value.to_owned(),
+        reason: reason.into(),
+    }
+}
+
+fn parse_number<T>(
+    strategy: &'static str,
+    param: &'static str,
+    value: &str,
+    range: RangeInclusive<T>,
+) -> Result<T, SpecError>
+where
+    T: FromStr + PartialOrd + std::fmt::Display,
+{
+    let reason = || {
+        format!(
+            "expected a number from {} to {}",
+            range.start(),
+            range.end()
+        )
+    };
+    let number = value
+        .parse()
+        .map_err(|_| invalid(strategy, param, value, reason()))?;
+    if !range.contains(&number) {
+        return Err(invalid(strategy, param, value, reason()));
+    }
+    Ok(number)
+}
+
+fn parse_bool(strategy: &'static str, param: &'static str, value: &str) -> Result<bool, SpecError> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(invalid(strategy, param, value, "expected true or false")),
+    }
+}
+
+/// Parses a single quality (`95`) or an inclusive range (`70-95`).
+fn parse_qualities(strategy: &'static str, value: &str) -> Result<RangeInclusive<u8>, SpecError> {
+    let (low, high) = value.split_once('-').unwrap_or((value, value));
+    let low = parse_number(strategy, "quality", low.trim(), 1..=100)?;
+    let high = parse_number(strategy, "quality", high.trim(), 1..=100)?;
+    quality_range(strategy, value, low, high)
+}
+
+/// Checks that a quality range is not empty.
+fn quality_range(
+    strategy: &'static str,
+    value: &str,
+    low: u8,
+    high: u8,
+) -> Result<RangeInclusive<u8>, SpecError> {
+    if low > high {
+        return Err(invalid(
+            strategy,
+            "quality",
+            value,
+            "the lower bound is above the upper bound",
+        ));
+    }
+    Ok(low..=high)
+}
+
+fn parse_subsampling(value: &str) -> Result<Subsampling, SpecError> {
+    use clap::ValueEnum;
+    Subsampling::from_str(value, true)
+        .map_err(|_| invalid("jpeg", "subsampling", value, "expected 444, 422 or 420"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Defaults {
+        Defaults {
+            min_quality: 70,
+            png_effort: 0,
+            palette_colors: None,
+            dither: false,
+            jpeg_subsampling: None,
+            progressive: false,
+            #[cfg(feature = "avif")]
+            avif_speed: 6,
+            #[cfg(feature = "avif")]
+            avif_max_quality: 90,
+        }
+    }
+
+    fn parse(spec: &str) -> Result<Vec<String>, SpecError> {
+        let chain = parse_chain(spec, &defaults())?;
+        Ok(chain.iter().map(|s| s.to_string()).collect())
+    }
+
+    // Shorthands and key=value pairs both fill in the parameters, the rest come from the defaults.
+    #[test]
+    fn test_parse_chain() {
+        let chain =
+            parse("png:best, webp:lossless, jpeg:95:420, jpeg:quality=60-85:progressive").unwrap();
+
+        assert_eq!(
+            chain,
+            [
+                "png:effort=0",
+                "webp:lossless=true",
+                "jpeg:quality=95-95:subsampling=420:progressive=false",
+                "jpeg:quality=60-85:progressive=true",
+            ]
+        );
+    }
+
+    // The displayed form of a strategy parses back to the same strategy.
+    #[test]
+    fn test_display_roundtrip() {
+        let displayed = parse("palette:64:dither,jpeg:80-90:422").unwrap().join(",");
+
+        assert_eq!(parse(&displayed).unwrap().join(","), displayed);
+    }
+
+    // An --avif-quality below -q is reported instead of leaving AVIF out, unless the spec gives
+    // its own range.
+    #[cfg(feature = "avif")]
+    #[test]
+    fn test_empty_avif_default_range() {
+        let defaults = Defaults {
+            avif_max_quality: 60,
+            ..defaults()
+        };
+
+        assert!(matches!(
+            default_chain(&defaults).err(),
+            Some(SpecError::InvalidValue {
+                strategy: "avif",
+                param: "quality",
+                ..
+            })
+        ));
+        assert!(parse_chain("avif", &defaults).is_err());
+        assert!(parse_chain("avif:50-60", &defaults).is_ok());
+    }
+
+    // -p adds the palette strategy before the lossy ones, unless the chain already has one.
+    #[test]
+    fn test_add_palette() {
+        let defaults = Defaults {
+            palette_colors: Some(64),
+            dither: true,
+            ..defaults()
+        };
+        let names = |spec: &str| {
+            let mut chain = parse_chain(spec, &defaults).unwrap();
+            add_palette(&mut chain, &defaults);
+            chain.iter().map(|s| s.to_string()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names("png,webp:lossless,jpeg"),
+            [
+                "png:effort=0",
+                "webp:lossless=true",
+                "palette:colors=64:dither=true",
+                "jpeg:quality=70-100:progressive=false",
+            ]
+        );
+        assert_eq!(names("png,palette:16")[1], "palette:colors=16:dither=true");
+        assert_eq!(names("png,palette:16").len(), 2);
+    }
+
+    // Unknown names, unknown parameters and bad values are reported.
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("png,gif").unwrap_err(),
+            SpecError::UnknownStrategy("gif".to_owned())
+        );
+        assert_eq!(parse("png,,jpeg").unwrap_err(), SpecError::Empty);
+        assert!(matches!(
+            parse("jpeg:fast").unwrap_err(),
+            SpecError::InvalidValue {
+                param: "quality",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse("jpeg:90-80").unwrap_err(),
+            SpecError::InvalidValue {
+                param: "quality",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse("palette:colors=1").unwrap_err(),
+            SpecError::InvalidValue {
+                param: "colors",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse("webp:lossless=maybe").unwrap_err(),
+            SpecError::InvalidValue { reason, .. } if reason == "expected true or false"
+        ));
+        assert!(matches!(
+            parse("webp:lossless=false").unwrap_err(),
+            SpecError::InvalidValue { reason, .. } if reason == "lossy WebP is not supported"
+        ));
+        assert!(matches!(
+            parse("webp:quality=80").unwrap_err(),
+            SpecError::UnknownParam {
+                strategy: "webp",
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,518 @@
+//! Encode strategies, tried in order until one of them fits in the target size.
+
+use crate::{Error, jpeg, metadata::Metadata, palette};
+use image::{DynamicImage, ImageFormat};
+use std::{fmt, ops::RangeInclusive};
+
+/// One way of encoding an image, along with its parameters.
+pub trait Strategy: fmt::Debug {
+    /// Short identifier, such as `png` or `jpeg`.
+    fn name(&self) -> &'static str;
+
+    /// The parameters as `key=value` pairs, in the form accepted by `--strategy`.
+    fn params(&self) -> Vec<(&'static str, String)>;
+
+    /// The format the output is written in.
+    fn format(&self) -> ImageFormat;
+
+    /// The extension of output files, without the leading dot.
+    fn extension(&self) -> &'static str {
+        self.format().extensions_str()[0]
+    }
+
+    /// Whether the strategy works on 8-bit images. 16-bit images are reduced before being passed
+    /// to it; only plain PNG keeps their depth.
+    fn wants_8bit(&self) -> bool {
+        false
+    }
+
+    /// Whether the output can embed an ICC profile. Profiles are dropped by strategies that cannot.
+    fn carries_icc_profile(&self) -> bool {
+        true
+    }
+
+    /// Encodes `image` along with as much of `metadata` as the format can store, returning `None`
+    /// if the result cannot be made smaller than `target_size`.
+    fn encode(
+        &self,
+        image: &DynamicImage,
+        metadata: &Metadata,
+        target_size: usize,
+    ) -> Result<Option<Encoded>, Error>;
+}
+
+impl fmt::Display for dyn Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())?;
+        for (key, value) in self.params() {
+            write!(f, ":{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of a strategy that managed to get below the target size.
+#[derive(Debug)]
+pub struct Encoded {
+    pub data: Vec<u8>,
+    /// The quality picked by the quality search, if the strategy is lossy
+    pub quality: Option<u8>,
+}
+
+impl Encoded {
+    /// Wraps the output of a lossless strategy, if it fits.
+    fn lossless(data: Vec<u8>, target_size: usize) -> Option<Self> {
+        (data.len() < target_size).then_some(Self {
+            data,
+            quality: None,
+        })
+    }
+}
+
+/// PNG filters tried by [`PngStrategy`], indexed by the effort level.
+const PNG_FILTERS_BY_EFFORT: &[&[image::codecs::png::FilterType]] = {
+    use image::codecs::png::FilterType::*;
+    &[
+        &[Adaptive],
+        &[Adaptive, Paeth, Sub, Up],
+        &[Adaptive, Paeth, Sub, Up, Avg, NoFilter],
+    ]
+};
+
+/// Maximum value accepted for [`PngStrategy::effort`].
+pub const MAX_PNG_EFFORT: u8 = PNG_FILTERS_BY_EFFORT.len() as u8 - 1;
+
+/// Lossless PNG with the strongest compression, keeping the smallest of several filters.
+#[derive(Debug, Clone, Default)]
+pub struct PngStrategy {
+    /// Index into `PNG_FILTERS_BY_EFFORT`
+    pub effort: u8,
+}
+
+impl Strategy for PngStrategy {
+    fn name(&self) -> &'static str {
+        "png"
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        vec![("effort", self.effort.to_string())]
+    }
+
+    fn format(&self) -> ImageFormat {
+        ImageFormat::Png
+    }
+
+    fn encode(
+        &self,
+        image: &DynamicImage,
+        metadata: &Metadata,
+        target_size: usize,
+    ) -> Result<Option<Encoded>, Error> {
+        use image::codecs::png::{CompressionType, PngEncoder};
+
+        let filters = PNG_FILTERS_BY_EFFORT[self.effort.min(MAX_PNG_EFFORT) as usize];
+        let mut smallest: Option<Vec<u8>> = None;
+        for &filter in filters {
+            let mut buf = Vec::new();
+            let mut encoder = PngEncoder::new_with_quality(&mut buf, CompressionType::Best, filter);
+            metadata.apply(&mut encoder);
+            image.write_with_encoder(encoder)?;
+            if smallest.as_ref().is_none_or(|s| buf.len() < s.len()) {
+                smallest = Some(buf);
+            }
+        }
+        Ok(smallest.and_then(|data| Encoded::lossless(data, target_size)))
+    }
+}
+
+/// Lossless WebP.
+#[derive(Debug, Clone, Default)]
+pub struct WebpLosslessStrategy;
+
+impl Strategy for WebpLosslessStrategy {
+    fn name(&self) -> &'static str {
+        "webp"
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        vec![("lossless", true.to_string())]
+    }
+
+    fn format(&self) -> ImageFormat {
+        ImageFormat::WebP
+    }
+
+    fn wants_8bit(&self) -> bool {
+        true
+    }
+
+    fn encode(
+        &self,
+        image: &DynamicImage,
+        metadata: &Metadata,
+        target_size: usize,
+    ) -> Result<Option<Encoded>, Error> {
+        use image::codecs::webp::WebPEncoder;
+
+        let mut buf = Vec::new();
+        let mut encoder = WebPEncoder::new_lossless(&mut buf);
+        metadata.apply(&mut encoder);
+        image.write_with_encoder(encoder)?;
+        Ok(Encoded::lossless(buf, target_size))
+    }
+}
+
+/// PNG quantized to a limited palette.
+#[derive(Debug, Clone)]
+pub struct PaletteStrategy {
+    /// Maximum number of colors (2-256)
+    pub colors: u16,
+    /// Whether to apply Floyd-Steinberg dithering
+    pub dither: bool,
+}
+
+impl Strategy for PaletteStrategy {
+    fn name(&self) -> &'static str {
+        "palette"
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("colors", self.colors.to_string()),
+            ("dither", self.dither.to_string()),
+        ]
+    }
+
+    fn format(&self) -> ImageFormat {
+        ImageFormat::Png
+    }
+
+    fn wants_8bit(&self) -> bool {
+        true
+    }
+
+    fn encode(
+        &self,
+        image: &DynamicImage,
+        metadata: &Metadata,
+        target_size: usize,
+    ) -> Result<Option<Encoded>, Error> {
+        let data = palette::encode_palette_png(image, self.colors, self.dither, metadata)?;
+        Ok(Encoded::lossless(data, target_size))
+    }
+}
+
+/// JPEG with the highest quality in `qualities` that fits.
+#[derive(Debug, Clone)]
+pub struct JpegStrategy {
+    pub qualities: RangeInclusive<u8>,
+    /// Chroma subsampling; `None` uses the `image` crate's encoder (4:4:4)
+    pub subsampling: Option<jpeg::Subsampling>,
+    /// Whether to write progressive JPEG (always uses the built-in encoder)
+    pub progressive: bool,
+}
+
+impl Strategy for JpegStrategy {
+    fn name(&self) -> &'static str {
+        "jpeg"
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![("quality", format_qualities(&self.qualities))];
+        if let Some(subsampling) = self.subsampling {
+            params.push(("subsampling", subsampling.to_string()));
+        }
+        params.push(("progressive", self.progressive.to_string()));
+        params
+    }
+
+    fn format(&self) -> ImageFormat {
+        ImageFormat::Jpeg
+    }
+
+    fn wants_8bit(&self) -> bool {
+        true
+    }
+
+    fn encode(
+        &self,
+        image: &DynamicImage,
+        metadata: &Metadata,
+        target_size: usize,
+    ) -> Result<Option<Encoded>, Error> {
+        use image::codecs::jpeg::JpegEncoder;
+
+        // The built-in encoder transforms the image once for all the qualities it tries
+        let transformed = if self.subsampling.is_some() || self.progressive {
+            let subsampling = self.subsampling.unwrap_or(jpeg::Subsampling::S444);
+            Some(jpeg::Transformed::new(image, subsampling)?)
+        } else {
+            None
+        };
+        search_quality(self.qualities.clone(), target_size, |quality| {
+            if let Some(transformed) = &transformed {
+                return Ok(transformed.encode(quality, self.progressive, metadata));
+            }
+            let mut buf = Vec::new();
+            let mut encoder = JpegEncoder::new_with_quality(&mut buf, quality);
+            metadata.apply(&mut encoder);
+            image.write_with_encoder(encoder)?;
+            Ok(buf)
+        })
+    }
+}
+
+/// AVIF with the highest quality in `qualities` that fits.
+#[cfg(feature = "avif")]
+#[derive(Debug, Clone)]
+pub struct AvifStrategy {
+    pub qualities: RangeInclusive<u8>,
+    /// Encoder speed, from 1 (slowest, smallest) to 10 (fastest)
+    pub speed: u8,
+}
+
+#[cfg(feature = "avif")]
+impl Strategy for AvifStrategy {
+    fn name(&self) -> &'static str {
+        "avif"
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("quality", format_qualities(&self.qualities)),
+            ("speed", self.speed.to_string()),
+        ]
+    }
+
+    fn format(&self) -> ImageFormat {
+        ImageFormat::Avif
+    }
+
+    fn wants_8bit(&self) -> bool {
+        true
+    }
+
+    fn carries_icc_profile(&self) -> bool {
+        // The AVIF encoder of the `image` crate writes no color profile
+        false
+    }
+
+    fn encode(
+        &self,
+        image: &DynamicImage,
+        metadata: &Metadata,
+        target_size: usize,
+    ) -> Result<Option<Encoded>, Error> {
+        use image::codecs::avif::AvifEncoder;
+
+        search_quality(self.qualities.clone(), target_size, |quality| {
+            let mut buf = Vec::new();
+            let mut encoder = AvifEncoder::new_with_speed_quality(&mut buf, self.speed, quality);
+            metadata.apply(&mut encoder);
+            image.write_with_encoder(encoder)?;
+            Ok(buf)
+        })
+    }
+}
+
+fn format_qualities(qualities: &RangeInclusive<u8>) -> String {
+    format!("{}-{}", qualities.start(), qualities.end())
+}
+
+/// Bisects over `qualities` for the highest quality whose output is below `target_size`.
+///
+/// The output size is assumed to grow monotonically with the quality.
+fn search_quality(
+    qualities: RangeInclusive<u8>,
+    target_size: usize,
+    mut encode: impl FnMut(u8) -> Result<Vec<u8>, Error>,
+) -> Result<Option<Encoded>, Error> {
+    let mut best = None;
+    let (mut low, mut high) = (*qualities.start().max(&1), *qualities.end().min(&100));
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let data = encode(quality)?;
+        if data.len() < target_size {
+            best = Some(Encoded {
+                data,
+                quality: Some(quality),
+            });
+            low = quality + 1;
+        } else if quality == low {
+            break;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The quality search picks the highest quality whose output is below the target.
+    #[test]
+    fn test_search_quality_picks_highest_fitting() {
+        let encode = |q: u8| Ok(vec![0; q as usize * 10]);
+
+        let encoded = search_quality(1..=100, 505, encode).unwrap().unwrap();
+
+        assert_eq!(encoded.quality, Some(50));
+        assert_eq!(encoded.data.len(), 500);
+    }
+
+    // The quality search never goes below the floor.
+    #[test]
+    fn test_search_quality_respects_floor() {
+        let encode = |q: u8| Ok(vec![0; q as usize * 10]);
+
+        assert!(search_quality(60..=100, 505, encode).unwrap().is_none());
+    }
+
+    // The lossless WebP strategy produces a .webp file with the exact same pixels.
+    #[test]
+    fn test_webp_lossless_roundtrip() {
+        let mut img = image::RgbImage::new(16, 16);
+        img.put_pixel(3, 5, image::Rgb([12, 34, 56]));
+        let img = DynamicImage::ImageRgb8(img);
+
+        let encoded = WebpLosslessStrategy
+            .encode(&img, &Metadata::default(), usize::MAX)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(WebpLosslessStrategy.extension(), "webp");
+        let decoded = image::load_from_memory(&encoded.data).unwrap();
+        assert_eq!(decoded.to_rgb8(), img.to_rgb8());
+    }
+
+    // Trying more PNG filters never gives a larger file than the adaptive filter alone.
+    #[test]
+    fn test_png_effort_never_larger() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8])
+        }));
+        let encode = |effort| {
+            PngStrategy { effort }
+                .encode(&img, &Metadata::default(), usize::MAX)
+                .unwrap()
+                .unwrap()
+        };
+
+        assert!(encode(MAX_PNG_EFFORT).data.len() <= encode(0).data.len());
+    }
+
+    // The JPEG strategy honors the requested chroma subsampling.
+    #[test]
+    fn test_jpeg_subsampling_option() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, 0, (y * 4) as u8])
+        }));
+        let encode = |subsampling| {
+            let strategy = JpegStrategy {
+                qualities: 70..=100,
+                subsampling: Some(subsampling),
+                progressive: false,
+            };
+            strategy
+                .encode(&img, &Metadata::default(), usize::MAX)
+                .unwrap()
+                .unwrap()
+        };
+
+        let full = encode(jpeg::Subsampling::S444);
+        let half = encode(jpeg::Subsampling::S420);
+
+        assert_eq!(full.quality, Some(100));
+        assert!(half.data.len() < full.data.len());
+    }
+
+    // The EXIF block and the ICC profile survive every strategy that writes a format able to
+    // store them.
+    #[test]
+    fn test_metadata_is_preserved() {
+        use image::ImageDecoder;
+
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(16, 16));
+        let exif = crate::metadata::tests::exif_with_gps();
+        let icc_profile = b"not really a profile, but the encoders do not care".repeat(2);
+        let metadata = Metadata {
+            exif: Some(exif.clone()),
+            icc_profile: Some(icc_profile.clone()),
+        };
+        let strategies: [Box<dyn Strategy>; 5] = [
+            Box::new(PngStrategy::default()),
+            Box::new(WebpLosslessStrategy),
+            Box::new(PaletteStrategy {
+                colors: 16,
+                dither: false,
+            }),
+            Box::new(JpegStrategy {
+                qualities: 90..=90,
+                subsampling: None,
+                progressive: false,
+            }),
+            Box::new(JpegStrategy {
+                qualities: 90..=90,
+                subsampling: Some(jpeg::Subsampling::S420),
+                progressive: true,
+            }),
+        ];
+
+        for strategy in &strategies {
+            let encoded = strategy
+                .encode(&img, &metadata, usize::MAX)
+                .unwrap()
+                .unwrap();
+            let mut decoder = image::ImageReader::new(std::io::Cursor::new(&encoded.data))
+                .with_guessed_format()
+                .unwrap()
+                .into_decoder()
+                .unwrap();
+
+            assert_eq!(
+                decoder.exif_metadata().unwrap().as_ref(),
+                Some(&exif),
+                "{strategy}"
+            );
+            assert!(strategy.carries_icc_profile());
+            assert_eq!(
+                decoder.icc_profile().unwrap().as_ref(),
+                Some(&icc_profile),
+                "{strategy}"
+            );
+        }
+    }
+
+    // Strategies display as their name followed by their parameters.
+    #[test]
+    fn test_display_name_and_params() {
+        let strategy: Box<dyn Strategy> = Box::new(PaletteStrategy {
+            colors: 64,
+            dither: true,
+        });
+
+        assert_eq!(strategy.to_string(), "palette:colors=64:dither=true");
+        assert_eq!(strategy.extension(), "png");
+    }
+
+    // The AVIF strategy reports the quality it settled on.
+    #[cfg(feature = "avif")]
+    #[test]
+    fn test_avif_reports_quality() {
+        let img = DynamicImage::new_rgb8(64, 64);
+        let strategy = AvifStrategy {
+            qualities: 70..=90,
+            speed: 10,
+        };
+
+        let encoded = strategy
+            .encode(&img, &Metadata::default(), usize::MAX)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(strategy.extension(), "avif");
+        assert_eq!(encoded.quality, Some(90));
+    }
+}
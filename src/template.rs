@@ -0,0 +1,232 @@
+//! Output filename templates, such as `{stem}-reenc.{ext}` or `{stem}_{width}x{height}_q{quality}.{ext}`.
+
+use std::{fmt, str::FromStr, time::SystemTime};
+
+/// The template used when `--name-template` is not given.
+pub const DEFAULT: &str = "{stem}-reenc.{ext}";
+
+/// Values a template can refer to, for one output file.
+#[derive(Debug, Clone)]
+pub struct Fields<'a> {
+    /// Input filename without its extension
+    pub stem: &'a str,
+    /// Extension of the output format, without the dot
+    pub ext: &'a str,
+    /// Name of the output format (`jpeg`, `png`, ...)
+    pub format: &'a str,
+    /// Quality picked by the quality search; empty for lossless output
+    pub quality: Option<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// Target size in bytes
+    pub target: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Stem,
+    Ext,
+    Format,
+    Quality,
+    Width,
+    Height,
+    Target,
+    Date,
+}
+
+impl Placeholder {
+    const ALL: [(&'static str, Placeholder); 8] = [
+        ("stem", Placeholder::Stem),
+        ("ext", Placeholder::Ext),
+        ("format", Placeholder::Format),
+        ("quality", Placeholder::Quality),
+        ("width", Placeholder::Width),
+        ("height", Placeholder::Height),
+        ("target", Placeholder::Target),
+        ("date", Placeholder::Date),
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A parsed `--name-template`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl NameTemplate {
+    /// Whether the name depends on the input's name; without it, every input maps to the same
+    /// file.
+    pub fn has_stem(&self) -> bool {
+        self.parts.contains(&Part::Placeholder(Placeholder::Stem))
+    }
+
+    /// Builds the output filename.
+    pub fn render(&self, fields: &Fields) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => name.push_str(text),
+                Part::Placeholder(placeholder) => match placeholder {
+                    Placeholder::Stem => name.push_str(fields.stem),
+                    Placeholder::Ext => name.push_str(fields.ext),
+                    Placeholder::Format => name.push_str(fields.format),
+                    Placeholder::Quality => {
+                        if let Some(quality) = fields.quality {
+                            name.push_str(&quality.to_string());
+                        }
+                    }
+                    Placeholder::Width => name.push_str(&fields.width.to_string()),
+                    Placeholder::Height => name.push_str(&fields.height.to_string()),
+                    Placeholder::Target => name.push_str(&fields.target.to_string()),
+                    Placeholder::Date => name.push_str(&today()),
+                },
+            }
+        }
+        name
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        DEFAULT.parse().expect("the default template is valid")
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if matches!(s, "" | "." | "..") {
+            return Err(format!("{s:?} is not a valid filename"));
+        }
+        if s.contains(['/', '\\']) {
+            return Err(format!(
+                "{s:?} contains a path separator; use --output-dir to choose the directory"
+            ));
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(format!("unmatched '}}' in {s:?}"));
+            }
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("unmatched '{{' in {s:?}"))?;
+            let name = &rest[start + 1..end];
+            let placeholder = Placeholder::ALL
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|&(_, p)| p)
+                .ok_or_else(|| {
+                    let names: Vec<_> = Placeholder::ALL.iter().map(|(n, _)| *n).collect();
+                    format!(
+                        "unknown placeholder {{{name}}} (expected one of: {})",
+                        names.join(", ")
+                    )
+                })?;
+            parts.push(Part::Placeholder(placeholder));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        if !parts.contains(&Part::Placeholder(Placeholder::Ext)) {
+            return Err(format!(
+                "{s:?} has no {{ext}}, so the extension would not match the output format"
+            ));
+        }
+        Ok(Self {
+            source: s.to_owned(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Today's date in UTC, as `YYYY-MM-DD`.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date in the proleptic Gregorian
+/// calendar (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields<'static> {
+        Fields {
+            stem: "shot",
+            ext: "jpg",
+            format: "jpeg",
+            quality: Some(92),
+            width: 1920,
+            height: 1080,
+            target: 15 << 20,
+        }
+    }
+
+    // Placeholders are replaced and the literal text around them is kept.
+    #[test]
+    fn test_render() {
+        let template: NameTemplate = "{stem}_{width}x{height}_q{quality}.{ext}".parse().unwrap();
+
+        assert_eq!(template.render(&fields()), "shot_1920x1080_q92.jpg");
+        assert_eq!(NameTemplate::default().render(&fields()), "shot-reenc.jpg");
+    }
+
+    // Templates that could leave the output directory or cannot be parsed are rejected.
+    #[test]
+    fn test_invalid_templates() {
+        assert!("../{stem}.{ext}".parse::<NameTemplate>().is_err());
+        assert!("out/{stem}.{ext}".parse::<NameTemplate>().is_err());
+        assert!("..".parse::<NameTemplate>().is_err());
+        assert!("{stem.{ext}".parse::<NameTemplate>().is_err());
+        assert!("{stem}}.{ext}".parse::<NameTemplate>().is_err());
+        assert!("{name}.{ext}".parse::<NameTemplate>().is_err());
+        assert!("{stem}.bmp".parse::<NameTemplate>().is_err());
+        assert!(!"upload.{ext}".parse::<NameTemplate>().unwrap().has_stem());
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+}
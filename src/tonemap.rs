@@ -0,0 +1,215 @@
+//! Tone mapping of HDR (floating-point) images, such as OpenEXR or Radiance HDR, to 8-bit sRGB.
+//!
+//! Float pixels are linear and unbounded, so converting them directly clips everything above 1.0
+//! to white. The curves here compress the highlights instead. 16-bit integer images are already
+//! display-referred sRGB, so they only need fewer bits, and only for the formats other than PNG,
+//! which keeps all 16 unless quantized to a palette.
+
+use image::{ColorType, DynamicImage, Rgb, Rgba};
+
+/// Curve used to compress the highlights.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Operator {
+    /// Reinhard's `x / (1 + x)`, extended with the white point; soft and low in contrast
+    Reinhard,
+    /// The filmic ACES approximation by Krzysztof Narkowicz; punchy, like a camera's JPEG
+    #[default]
+    Aces,
+    /// John Hable's filmic curve (Uncharted 2); keeps more shadow detail than ACES
+    Hable,
+}
+
+/// White point of the Hable curve when none is given, as in the original.
+const HABLE_WHITE_POINT: f32 = 11.2;
+
+/// Tone mapping settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMap {
+    pub operator: Operator,
+    /// Exposure adjustment in stops, applied before the curve
+    pub exposure: f32,
+    /// Linear value (after the exposure) that becomes white; `None` uses the curve as it is
+    pub white_point: Option<f32>,
+}
+
+impl Default for ToneMap {
+    fn default() -> Self {
+        Self {
+            operator: Operator::default(),
+            exposure: 0.0,
+            white_point: None,
+        }
+    }
+}
+
+impl ToneMap {
+    /// Converts a floating-point image to 8-bit sRGB, keeping the alpha channel. Returns `None` for
+    /// other images.
+    pub fn apply(&self, image: &DynamicImage) -> Option<DynamicImage> {
+        match image {
+            DynamicImage::ImageRgb32F(buf) => {
+                let out = image::RgbImage::from_fn(buf.width(), buf.height(), |x, y| {
+                    let Rgb(rgb) = *buf.get_pixel(x, y);
+                    Rgb(rgb.map(|c| self.map_channel(c)))
+                });
+                Some(out.into())
+            }
+            DynamicImage::ImageRgba32F(buf) => {
+                let out = image::RgbaImage::from_fn(buf.width(), buf.height(), |x, y| {
+                    let Rgba([r, g, b, a]) = *buf.get_pixel(x, y);
+                    let [r, g, b] = [r, g, b].map(|c| self.map_channel(c));
+                    Rgba([r, g, b, to_u8(a)])
+                });
+                Some(out.into())
+            }
+            _ => None,
+        }
+    }
+
+    /// Maps one linear channel value to an sRGB-encoded byte.
+    fn map_channel(&self, value: f32) -> u8 {
+        // NaN and negative values (out of gamut) become black
+        let x = (value * self.exposure.exp2()).max(0.0);
+        let mapped = match (self.operator, self.white_point) {
+            (Operator::Reinhard, None) => x / (1.0 + x),
+            (Operator::Reinhard, Some(white)) => x * (1.0 + x / (white * white)) / (1.0 + x),
+            (Operator::Aces, None) => aces(x),
+            (Operator::Aces, Some(white)) => aces(x) / aces(white),
+            (Operator::Hable, white) => hable(x) / hable(white.unwrap_or(HABLE_WHITE_POINT)),
+        };
+        to_u8(linear_to_srgb(mapped))
+    }
+}
+
+/// Reduces a 16-bit image to 8 bits per channel. Returns `None` for other images.
+pub fn to_8bit(image: &DynamicImage) -> Option<DynamicImage> {
+    match image.color() {
+        ColorType::L16 => Some(image.to_luma8().into()),
+        ColorType::La16 => Some(image.to_luma_alpha8().into()),
+        ColorType::Rgb16 => Some(image.to_rgb8().into()),
+        ColorType::Rgba16 => Some(image.to_rgba8().into()),
+        _ => None,
+    }
+}
+
+/// Parses a `--white-point`, which has to be a positive number.
+pub fn parse_white_point(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+        _ => Err(format!("{s:?} is not a positive number")),
+    }
+}
+
+/// Parses an `--exposure` in stops.
+pub fn parse_exposure(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(format!("{s:?} is not a number")),
+    }
+}
+
+fn aces(x: f32) -> f32 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+/// The sRGB transfer function.
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(tone_map: ToneMap, value: f32) -> u8 {
+        tone_map.map_channel(value)
+    }
+
+    // Every curve keeps black black, grows with the input and reaches white at the white point.
+    #[test]
+    fn test_curves() {
+        for operator in [Operator::Reinhard, Operator::Aces, Operator::Hable] {
+            let tone_map = ToneMap {
+                operator,
+                white_point: Some(8.0),
+                ..ToneMap::default()
+            };
+            let values: Vec<u8> = [0.0, 0.05, 0.5, 2.0, 8.0]
+                .iter()
+                .map(|&v| map(tone_map, v))
+                .collect();
+
+            assert_eq!(values[0], 0, "{operator:?}");
+            assert!(
+                values.is_sorted_by(|a, b| a < b),
+                "{operator:?}: {values:?}"
+            );
+            assert_eq!(values[4], 255, "{operator:?}");
+            assert_eq!(map(tone_map, 100.0), 255, "{operator:?}");
+            assert_eq!(map(tone_map, f32::NAN), 0, "{operator:?}");
+        }
+
+        let plain = ToneMap {
+            operator: Operator::Reinhard,
+            ..ToneMap::default()
+        };
+        // 1.0 maps to 0.5, which is 188 in sRGB
+        assert_eq!(map(plain, 1.0), 188);
+        let darker = ToneMap {
+            exposure: -1.0,
+            ..plain
+        };
+        assert_eq!(map(darker, 1.0), map(plain, 0.5));
+    }
+
+    // Only float images are converted, and the alpha channel is kept.
+    #[test]
+    fn test_apply() {
+        let hdr = image::Rgba32FImage::from_pixel(2, 2, Rgba([4.0, 1.0, 0.0, 0.5]));
+        let mapped = ToneMap::default().apply(&hdr.into()).unwrap();
+
+        let DynamicImage::ImageRgba8(mapped) = mapped else {
+            panic!("expected RGBA8, got {:?}", mapped.color());
+        };
+        let Rgba([r, g, b, a]) = *mapped.get_pixel(1, 1);
+        assert!(r > g && g > b, "{r} {g} {b}");
+        assert!(r < 255, "highlights are compressed, not clipped");
+        assert_eq!(a, 128);
+
+        let sdr = DynamicImage::new_rgb16(2, 2);
+        assert!(ToneMap::default().apply(&sdr).is_none());
+    }
+
+    // 16-bit images are brought down to 8 bits with their channels kept; others are left alone.
+    #[test]
+    fn test_to_8bit() {
+        let deep = image::ImageBuffer::from_pixel(2, 2, Rgba([65535u16, 32896, 0, 65535]));
+
+        let reduced = to_8bit(&deep.into()).unwrap();
+
+        assert_eq!(reduced.color(), ColorType::Rgba8);
+        assert_eq!(
+            reduced.to_rgba8().get_pixel(0, 0),
+            &Rgba([255, 128, 0, 255])
+        );
+        assert!(to_8bit(&DynamicImage::new_rgb8(2, 2)).is_none());
+    }
+}
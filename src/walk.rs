@@ -0,0 +1,232 @@
+//! Expansion of directory inputs into the image files below them.
+
+use image::ImageFormat;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Which files to take from directory inputs.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Glob patterns files must match; if empty, every file in a readable image format is taken
+    pub include: Vec<String>,
+    /// Glob patterns for files and directories to leave out
+    pub exclude: Vec<String>,
+    /// How many directory levels to descend into; 1 only takes the files directly inside
+    pub max_depth: Option<u32>,
+}
+
+impl Filter {
+    fn excludes(&self, relative: &str) -> bool {
+        self.exclude.iter().any(|p| matches_path(p, relative))
+    }
+
+    fn includes(&self, path: &Path, relative: &str) -> bool {
+        if self.include.is_empty() {
+            return ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled());
+        }
+        self.include.iter().any(|p| matches_path(p, relative))
+    }
+}
+
+/// Replaces each directory in `inputs` with the files below it, in name order. Other inputs are
+/// kept as they are.
+pub fn expand_inputs(inputs: &[PathBuf], filter: &Filter) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        if input.is_dir() {
+            walk(input, "", 1, filter, &mut files)?;
+        } else {
+            files.push(input.clone());
+        }
+    }
+    Ok(files)
+}
+
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    depth: u32,
+    filter: &Filter,
+    files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name();
+        let relative = format!("{prefix}{}", name.to_string_lossy());
+        if filter.excludes(&relative) {
+            continue;
+        }
+        // Symlinked directories are not followed, to stay clear of loops
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if filter.max_depth.is_none_or(|max| depth < max) {
+                walk(&path, &format!("{relative}/"), depth + 1, filter, files)?;
+            }
+        } else if path.is_file() && !is_own_output(&path) && filter.includes(&path, &relative) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` looks like a file written by an earlier run (`*-reenc.*`).
+fn is_own_output(path: &Path) -> bool {
+    path.file_stem()
+        .is_some_and(|stem| stem.to_string_lossy().ends_with("-reenc"))
+}
+
+/// Matches a pattern against a `/`-separated path relative to the directory input. Patterns
+/// without a `/` only look at the last component, so `*.png` matches at any depth.
+fn matches_path(pattern: &str, relative: &str) -> bool {
+    if pattern.contains('/') {
+        glob_match(pattern, relative)
+    } else {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        glob_match(pattern, name)
+    }
+}
+
+/// Matches `text` against a glob pattern: `*` and `?` do not cross `/`, `**` does, and `[...]`
+/// is a character class (`[a-z]`, `[!0-9]`).
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&pattern, &text)
+}
+
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // `**/` also matches no directory at all
+            if let ['/', after @ ..] = rest
+                && glob_match_chars(after, text)
+            {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match_chars(rest, &text[i..]))
+        }
+        ['*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if glob_match_chars(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        ['?', rest @ ..] => {
+            matches!(text.first(), Some(&c) if c != '/') && glob_match_chars(rest, &text[1..])
+        }
+        ['[', class @ ..] => match match_class(class, text.first().copied()) {
+            Some((matched, rest)) => matched && glob_match_chars(rest, &text[1..]),
+            // No closing bracket: a literal `[`
+            None => text.first() == Some(&'[') && glob_match_chars(class, &text[1..]),
+        },
+        [c, rest @ ..] => text.first() == Some(c) && glob_match_chars(rest, &text[1..]),
+    }
+}
+
+/// Matches `c` against the character class starting right after a `[`. Returns whether it
+/// matched and the pattern after the closing `]`, or `None` if the class is not closed.
+fn match_class(class: &[char], c: Option<char>) -> Option<(bool, &[char])> {
+    let (negated, mut rest) = match class {
+        ['!' | '^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match rest {
+            [] => return None,
+            [']', after @ ..] if !first => {
+                let matched = c.is_some_and(|c| c != '/' && matched != negated);
+                return Some((matched, after));
+            }
+            [low, '-', high, after @ ..] if *high != ']' => {
+                matched |= c.is_some_and(|c| (*low..=*high).contains(&c));
+                rest = after;
+            }
+            [single, after @ ..] => {
+                matched |= c == Some(*single);
+                rest = after;
+            }
+        }
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+
+    // `*` and `?` stay within one path component, `**` spans several.
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.png", "shot.png"));
+        assert!(!glob_match("*.png", "dir/shot.png"));
+        assert!(glob_match("**/*.png", "shot.png"));
+        assert!(glob_match("**/*.png", "a/b/shot.png"));
+        assert!(glob_match("shot-??.jpg", "shot-01.jpg"));
+        assert!(glob_match("shot-[0-9][!a]*", "shot-1b.png"));
+        assert!(!glob_match("shot-[0-9]*", "shot-x.png"));
+        assert!(glob_match("[literal", "[literal"));
+    }
+
+    // Directories are walked in name order, honoring the filters and leaving out earlier outputs.
+    #[test]
+    fn test_expand_inputs() {
+        let dir = TempDir::new();
+        let root = dir.path().to_owned();
+        for file in [
+            "b.png",
+            "a.jpg",
+            "a-reenc.jpg",
+            "notes.txt",
+            "sub/c.png",
+            "sub/deeper/d.png",
+            "skip/e.png",
+        ] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let names = |filter: &Filter| -> Vec<String> {
+            expand_inputs(std::slice::from_ref(&root), filter)
+                .unwrap()
+                .iter()
+                .map(|p| {
+                    let relative = p.strip_prefix(&root).unwrap();
+                    relative.to_string_lossy().replace('\\', "/")
+                })
+                .collect()
+        };
+
+        let all = names(&Filter::default());
+        let limited = names(&Filter {
+            include: vec!["*.png".to_owned()],
+            exclude: vec!["skip".to_owned()],
+            max_depth: Some(2),
+        });
+
+        assert_eq!(
+            all,
+            [
+                "a.jpg",
+                "b.png",
+                "skip/e.png",
+                "sub/c.png",
+                "sub/deeper/d.png"
+            ]
+        );
+        assert_eq!(limited, ["b.png", "sub/c.png"]);
+    }
+}